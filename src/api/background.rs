@@ -0,0 +1,277 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use slog::{info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{delay_until, Instant};
+
+use super::environment;
+use super::gql::Context;
+use crate::metrics;
+use crate::settings::{Env, Settings};
+
+/// The interval at which an environment is probed in the background, if any: its own interval,
+/// or --probe-interval.
+pub fn probe_interval(env: &Env, settings: &Settings) -> Option<Duration> {
+    env.interval
+        .or(settings.probe_interval)
+        .map(Duration::from_secs)
+}
+
+/// How often the loop checks for environments added by a reload, when it has nothing else to do
+const RECONFIGURE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Probe each environment at its own interval. The results are stored in the context, where
+/// queries pick them up. The environments which are due are probed concurrently, so that a slow
+/// environment does not delay the others. With --request-budget, they are probed one after the
+/// other instead, and those which are due once the budget of a cycle is spent are deferred to the
+/// next cycle. Environments added or removed by a reload of the configuration are picked up at
+/// the next cycle.
+pub async fn probe_loop(context: Context) {
+    // Only the requests of the background probes count against the budget.
+    let context = Context {
+        outbound_requests: Arc::new(AtomicU64::new(0)),
+        ..context
+    };
+    let mut rng = match context.settings.probe_jitter_seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut next_runs: HashMap<String, Instant> = HashMap::new();
+    loop {
+        let context = context.snapshot();
+        tick(&context);
+        // The retries of all probes, in the background or not, share the budget of the cycle.
+        if let Some(budget) = context.settings.retry_budget {
+            context.retry_tokens.store(budget, Ordering::Relaxed);
+        }
+        schedule(&context, &mut next_runs, &mut rng);
+        // The most overdue environments come first, so that deferred environments are not
+        // deferred again.
+        let now = Instant::now();
+        let mut due: Vec<(&Env, Duration)> = context
+            .envs
+            .values()
+            .filter_map(|env| Some((env, probe_interval(env, &context.settings)?)))
+            .filter(|(env, _)| next_runs[&env.env] <= now)
+            .collect();
+        due.sort_by_key(|(env, _)| next_runs[&env.env]);
+        let probes = async {
+            match context.settings.request_budget {
+                Some(budget) => probe_within_budget(&context, due, budget, &mut next_runs).await,
+                None => {
+                    probe_concurrently(&context, due, &mut next_runs).await;
+                    Vec::new()
+                }
+            }
+        };
+        let deferred = ticking(&context, RECONFIGURE_CHECK_INTERVAL, probes).await;
+        if !deferred.is_empty() {
+            warn!(
+                context.logger,
+                "Request budget of this probe cycle is spent, deferring environments {} to the next cycle",
+                deferred.join(", ")
+            );
+        }
+        // Deferred environments are overdue, so they don't set the time of the next cycle.
+        let next_run = next_runs
+            .iter()
+            .filter(|(name, _)| !deferred.contains(&name.as_str()))
+            .map(|(_, next_run)| *next_run)
+            .min();
+        let next_check = Instant::now() + RECONFIGURE_CHECK_INTERVAL;
+        delay_until(next_run.map_or(next_check, |next_run| next_run.min(next_check))).await;
+    }
+}
+
+// Probe the environments which are due all at once, and schedule the next probe of each of them
+// as soon as it completes.
+async fn probe_concurrently(
+    context: &Context,
+    due: Vec<(&Env, Duration)>,
+    next_runs: &mut HashMap<String, Instant>,
+) {
+    let mut probes: FuturesUnordered<_> = due
+        .into_iter()
+        .map(|(env, interval)| async move {
+            // Failures are already logged and reported in the probe results.
+            let _ = environment::probe_catching_panics(env, context, Default::default()).await;
+            (env, interval)
+        })
+        .collect();
+    while let Some((env, interval)) = probes.next().await {
+        next_runs.insert(env.env.clone(), Instant::now() + interval);
+        tick(context);
+    }
+}
+
+// Probe the environments which are due one after the other, until the requests they sent exceed
+// the budget of the cycle, and return the names of those which were deferred. The first one is
+// always probed.
+async fn probe_within_budget<'a>(
+    context: &'a Context,
+    due: Vec<(&'a Env, Duration)>,
+    budget: u64,
+    next_runs: &mut HashMap<String, Instant>,
+) -> Vec<&'a str> {
+    let cycle_start = context.outbound_requests.load(Ordering::Relaxed);
+    let mut deferred: Vec<&str> = Vec::new();
+    for (i, (env, interval)) in due.into_iter().enumerate() {
+        let spent = context.outbound_requests.load(Ordering::Relaxed) - cycle_start;
+        if i > 0 && spent >= budget {
+            deferred.push(&env.env);
+            continue;
+        }
+        // Failures are already logged and reported in the probe results.
+        let _ = environment::probe_catching_panics(env, context, Default::default()).await;
+        next_runs.insert(env.env.clone(), Instant::now() + interval);
+        tick(context);
+    }
+    deferred
+}
+
+// Show that the loop is running.
+fn tick(context: &Context) {
+    *context.last_loop_tick.lock().unwrap() = std::time::Instant::now();
+}
+
+// Run the probes of a cycle, ticking at the given interval until they are done: a probe can take
+// longer than the health window of the loop (several requests, each up to --request-timeout, and
+// their retries), which does not mean the loop is dead.
+async fn ticking<F: Future>(context: &Context, interval: Duration, probes: F) -> F::Output {
+    let mut ticks = tokio::time::interval(interval);
+    futures::pin_mut!(probes);
+    loop {
+        tokio::select! {
+            output = &mut probes => return output,
+            _ = ticks.tick() => tick(context),
+        }
+    }
+}
+
+/// Whether the loop ticked within twice its interval: the longest of --probe-interval and the
+/// interval at which it checks for reloads. A loop which died (eg after a panic) otherwise goes
+/// unnoticed, with queries served from results which no longer change. Also updates
+/// besp_probe_loop_healthy.
+pub fn loop_healthy(context: &Context) -> bool {
+    let interval = context
+        .settings
+        .probe_interval
+        .map(Duration::from_secs)
+        .map_or(RECONFIGURE_CHECK_INTERVAL, |interval| {
+            interval.max(RECONFIGURE_CHECK_INTERVAL)
+        });
+    let healthy = context.last_loop_tick.lock().unwrap().elapsed() <= 2 * interval;
+    metrics::PROBE_LOOP_HEALTHY.set(if healthy { 1 } else { 0 });
+    healthy
+}
+
+// Schedule the first probe of the environments probed in the background which are not scheduled
+// yet, and forget those which are no longer probed in the background.
+fn schedule(context: &Context, next_runs: &mut HashMap<String, Instant>, rng: &mut StdRng) {
+    next_runs.retain(|name, _| {
+        context
+            .envs
+            .get(name)
+            .and_then(|env| probe_interval(env, &context.settings))
+            .is_some()
+    });
+    // Environments are sorted, so that a given seed always gives the same delays.
+    let mut envs: Vec<&Env> = context
+        .envs
+        .values()
+        .filter(|env| !next_runs.contains_key(&env.env))
+        .collect();
+    envs.sort_by(|a, b| a.env.cmp(&b.env));
+    for env in envs {
+        if let Some(interval) = probe_interval(env, &context.settings) {
+            let delay = first_probe_delay(interval, context.settings.probe_jitter, rng);
+            info!(
+                context.logger,
+                "Probing environment {} every {} seconds, starting in {} ms",
+                env.env,
+                interval.as_secs(),
+                delay.as_millis()
+            );
+            next_runs.insert(env.env.clone(), Instant::now() + delay);
+        }
+    }
+}
+
+// A random delay before the first probe of an environment, within both the jitter and the
+// interval of the environment, so that environments are not all probed at the same time.
+fn first_probe_delay(interval: Duration, jitter: Option<u64>, rng: &mut StdRng) -> Duration {
+    let max = match jitter {
+        Some(jitter) => Duration::from_secs(jitter).min(interval),
+        None => return Duration::from_secs(0),
+    };
+    if max.as_millis() == 0 {
+        return max;
+    }
+    Duration::from_millis(rng.gen_range(0, max.as_millis() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::testing::{context, settings};
+
+    fn delays(seed: u64, interval: Duration, jitter: Option<u64>) -> Vec<Duration> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..20)
+            .map(|_| first_probe_delay(interval, jitter, &mut rng))
+            .collect()
+    }
+
+    #[test]
+    fn first_probe_delay_is_reproducible_with_a_seed() {
+        let interval = Duration::from_secs(60);
+        assert_eq!(
+            delays(42, interval, Some(30)),
+            delays(42, interval, Some(30))
+        );
+        assert_ne!(
+            delays(42, interval, Some(30)),
+            delays(43, interval, Some(30))
+        );
+    }
+
+    #[test]
+    fn first_probe_delay_is_bounded_by_the_jitter_and_the_interval() {
+        let interval = Duration::from_secs(60);
+        assert!(delays(42, interval, Some(30))
+            .iter()
+            .all(|delay| *delay < Duration::from_secs(30)));
+        assert!(delays(42, Duration::from_secs(10), Some(30))
+            .iter()
+            .all(|delay| *delay < Duration::from_secs(10)));
+        assert!(delays(42, interval, None)
+            .iter()
+            .all(|delay| *delay == Duration::from_secs(0)));
+    }
+
+    #[tokio::test]
+    async fn slow_probes_keep_the_loop_healthy() {
+        let context = context(Settings {
+            probe_interval: Some(1),
+            ..settings()
+        });
+        // The loop started its cycle long ago, and its probes are still running.
+        let started = std::time::Instant::now() - Duration::from_secs(60);
+        *context.last_loop_tick.lock().unwrap() = started;
+        assert!(!loop_healthy(&context));
+        let probes = async {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            42
+        };
+        assert_eq!(
+            ticking(&context, Duration::from_millis(10), probes).await,
+            42
+        );
+        assert!(loop_healthy(&context));
+    }
+}
@@ -1,14 +1,32 @@
 use chrono::prelude::*;
-use futures::future::TryFutureExt;
-use futures::stream::{self, TryStreamExt};
-use juniper::{GraphQLEnum, GraphQLObject};
+use chrono::Duration;
+use futures::future::{self, FutureExt, TryFutureExt};
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt, TryStreamExt};
+use glob::Pattern;
+use juniper::parser::ScalarToken;
+use juniper::{
+    GraphQLEnum, GraphQLObject, InputValue, ParseScalarResult, ParseScalarValue, ScalarValue, Value,
+};
 use serde::{Deserialize, Serialize};
+use slog::{debug, warn};
 use snafu::ResultExt;
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryFrom;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
 use url::Url;
 
+use super::background;
+use super::events;
 use super::gql::Context;
 use crate::error;
+use crate::metrics;
+use crate::settings::{BragiApiVersion, CacheMode, Env, Settings};
 
 /// The response body for multiple indexes
 #[derive(Debug, Serialize, GraphQLObject)]
@@ -16,14 +34,27 @@ use crate::error;
 pub struct MultiEnvironmentsResponseBody {
     environments: Vec<BragiInfo>,
     environments_count: i32,
+    /// Changes when the digest of any environment does, whatever the order of the environments
+    pub digest: String,
 }
 
 impl From<Vec<BragiInfo>> for MultiEnvironmentsResponseBody {
     fn from(environments: Vec<BragiInfo>) -> Self {
         let environments_count = i32::try_from(environments.len()).unwrap();
+        let digests: BTreeMap<&str, &str> = environments
+            .iter()
+            .map(|info| (info.label.as_str(), info.digest.as_str()))
+            .collect();
+        let mut digest = Digest::new();
+        for (label, env_digest) in digests {
+            digest.write(label);
+            digest.write(env_digest);
+        }
+        let digest = digest.finish();
         Self {
             environments,
             environments_count,
+            digest,
         }
     }
 }
@@ -41,35 +72,267 @@ pub enum PrivateStatus {
 pub enum ServerStatus {
     Available,
     NotAvailable,
+    /// The server answered, but we could not parse its response
+    NotReadable,
+    /// The server was not asked (eg when the indices are not listed)
+    NotChecked,
+}
+
+/// The health of an index or a cluster, as reported by elasticsearch
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, GraphQLEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexHealth {
+    Green,
+    Yellow,
+    Red,
+}
+
+impl FromStr for IndexHealth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "green" => Ok(IndexHealth::Green),
+            "yellow" => Ok(IndexHealth::Yellow),
+            "red" => Ok(IndexHealth::Red),
+            _ => Err(format!("Unknown index health {}", s)),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone, GraphQLEnum)]
 #[serde(rename_all = "snake_case")]
 pub enum BragiStatus {
     Available,
+    /// Bragi and elasticsearch answer, but the indices could not be listed, or fewer than
+    /// expected are served
+    Degraded,
     BragiNotAvailable,
     ElasticsearchNotAvailable,
 }
 
-#[derive(Debug, Serialize, GraphQLObject)]
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct BragiInfo {
     pub label: String,
     pub url: String,
+    /// The team owning the environment, if any
+    pub owner: Option<String>,
     pub version: String,
+    /// False when bragi reports another version than the environment's expected_version
+    pub version_matches_expected: bool,
     pub status: BragiStatus,
     pub updated_at: DateTime<Utc>,
+    /// When the probe producing this information started
+    pub last_probed_at: DateTime<Utc>,
+    /// When the status last changed from the one of a previous probe (None until it does)
+    pub status_changed_at: Option<DateTime<Utc>>,
+    /// Whether a TCP connection to bragi could be opened (None when it was not checked)
+    pub tcp_reachable: Option<bool>,
+    /// Whether bragi answered HTTP requests
+    pub http_reachable: bool,
+    /// True when part of the probe succeeded and part failed
+    pub partial: bool,
+    /// Why the probe only partially succeeded
+    pub degraded_reason: Option<String>,
+    /// Why the probe failed
+    pub error: Option<String>,
     pub elastic: Option<ElasticsearchInfo>,
+    /// Whether bragi answered a reverse geocoding request (None when it was not checked)
+    pub reverse_ok: Option<bool>,
+    /// Whether bragi found results for the text of --search-query (None when it was not checked)
+    pub search_ok: Option<bool>,
+    /// The coverage searched for the text of --search-query, if any
+    pub search_coverage: Option<String>,
+    /// The metrics of bragi selected with --bragi-metric
+    pub bragi_metrics: Vec<BragiMetric>,
+    /// How long each phase of the probe took
+    pub timing: ProbeTiming,
+    /// Moving average of the duration (in milliseconds) of the probes of this environment
+    pub avg_probe_ms: Option<f64>,
+    /// A hash of the status, versions and indices, to tell whether anything changed
+    pub digest: String,
+}
+
+#[juniper::graphql_object]
+impl BragiInfo {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The team owning the environment, if any
+    fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// False when bragi reports another version than the environment's expected_version
+    fn version_matches_expected(&self) -> bool {
+        self.version_matches_expected
+    }
+
+    fn status(&self) -> BragiStatus {
+        self.status.clone()
+    }
+
+    fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+    }
+
+    /// When the probe producing this information started
+    fn last_probed_at(&self) -> DateTime<Utc> {
+        self.last_probed_at
+    }
+
+    /// When the status last changed from the one of a previous probe (null until it does)
+    fn status_changed_at(&self) -> Option<DateTime<Utc>> {
+        self.status_changed_at
+    }
+
+    /// How long ago this information was updated
+    fn staleness(&self) -> DurationSeconds {
+        DurationSeconds::from(Utc::now().signed_duration_since(self.updated_at))
+    }
+
+    /// Whether a TCP connection to bragi could be opened (null when it was not checked)
+    fn tcp_reachable(&self) -> Option<bool> {
+        self.tcp_reachable
+    }
+
+    /// Whether bragi answered HTTP requests
+    fn http_reachable(&self) -> bool {
+        self.http_reachable
+    }
+
+    /// True when part of the probe succeeded and part failed
+    fn partial(&self) -> bool {
+        self.partial
+    }
+
+    /// Why the probe only partially succeeded
+    fn degraded_reason(&self) -> Option<&str> {
+        self.degraded_reason.as_deref()
+    }
+
+    /// Why the probe failed
+    fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    fn elastic(&self) -> Option<&ElasticsearchInfo> {
+        self.elastic.as_ref()
+    }
+
+    /// Whether bragi answered a reverse geocoding request (null when it was not checked)
+    fn reverse_ok(&self) -> Option<bool> {
+        self.reverse_ok
+    }
+
+    /// Whether bragi found results for the text of --search-query (null when it was not checked)
+    fn search_ok(&self) -> Option<bool> {
+        self.search_ok
+    }
+
+    /// The coverage searched for the text of --search-query, if any
+    fn search_coverage(&self) -> Option<&str> {
+        self.search_coverage.as_deref()
+    }
+
+    /// The metrics of bragi selected with --bragi-metric
+    fn bragi_metrics(&self) -> &[BragiMetric] {
+        &self.bragi_metrics
+    }
+
+    /// How long each phase of the probe took
+    fn timing(&self) -> &ProbeTiming {
+        &self.timing
+    }
+
+    /// Moving average of the duration (in milliseconds) of the probes of this environment
+    fn avg_probe_ms(&self) -> Option<f64> {
+        self.avg_probe_ms
+    }
+
+    /// A hash of the status, versions and indices, which only changes when they do
+    fn digest(&self) -> &str {
+        &self.digest
+    }
+}
+
+/// A duration, in whole seconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct DurationSeconds(pub i32);
+
+impl From<Duration> for DurationSeconds {
+    fn from(duration: Duration) -> Self {
+        let seconds = duration.num_seconds().max(0);
+        DurationSeconds(i32::try_from(seconds).unwrap_or(i32::MAX))
+    }
+}
+
+#[juniper::graphql_scalar(description = "A duration, in seconds")]
+impl<S> GraphQLScalar for DurationSeconds
+where
+    S: ScalarValue,
+{
+    fn resolve(&self) -> Value {
+        Value::scalar(self.0)
+    }
+
+    fn from_input_value(value: &InputValue) -> Option<DurationSeconds> {
+        value.as_int_value().map(DurationSeconds)
+    }
+
+    fn from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+        <i32 as ParseScalarValue<S>>::from_str(value)
+    }
+}
+
+/// The time (in milliseconds) spent in each phase of a probe. A phase which was not reached
+/// because an earlier one failed has no time.
+#[derive(Debug, Default, Serialize, Clone, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeTiming {
+    /// Checking that bragi answers
+    pub accessible_ms: Option<i32>,
+    /// Reading bragi's status
+    pub status_ms: Option<i32>,
+    /// Listing the elasticsearch indices
+    pub indices_ms: Option<i32>,
 }
 
 impl BragiInfo {
-    fn new<S: Into<String>>(label: S, url: S) -> BragiInfo {
+    fn new<S: Into<String>>(label: S, url: S, last_probed_at: DateTime<Utc>) -> BragiInfo {
         BragiInfo {
             label: label.into(),
             url: url.into(),
+            owner: None,
             version: String::from(""),
+            version_matches_expected: true,
             status: BragiStatus::BragiNotAvailable,
             updated_at: Utc::now(),
+            last_probed_at,
+            status_changed_at: None,
+            tcp_reachable: None,
+            http_reachable: false,
+            partial: false,
+            degraded_reason: None,
+            error: None,
             elastic: None,
+            reverse_ok: None,
+            search_ok: None,
+            search_coverage: None,
+            bragi_metrics: Vec::new(),
+            timing: ProbeTiming::default(),
+            avg_probe_ms: None,
+            digest: String::new(),
         }
     }
 }
@@ -79,12 +342,62 @@ impl BragiInfo {
 #[derive(Debug, Deserialize, GraphQLObject)]
 pub struct BragiStatusDetails {
     pub version: String,
-    #[serde(rename = "es")]
+    // Depending on its version, bragi uses one of these names for the elasticsearch url.
+    #[serde(rename = "es", alias = "elasticsearch", alias = "elastic_search")]
     pub elasticsearch: String,
     pub status: String,
 }
 
-#[derive(Debug, Serialize, Clone, GraphQLObject)]
+// Newer versions of bragi nest the information returned by 'bragi/status'.
+#[derive(Debug, Deserialize)]
+struct BragiStatusDetailsV2 {
+    bragi: BragiComponentDetails,
+    elasticsearch: ElasticsearchComponentDetails,
+}
+
+#[derive(Debug, Deserialize)]
+struct BragiComponentDetails {
+    version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ElasticsearchComponentDetails {
+    url: String,
+    status: String,
+}
+
+impl From<BragiStatusDetailsV2> for BragiStatusDetails {
+    fn from(details: BragiStatusDetailsV2) -> Self {
+        BragiStatusDetails {
+            version: details.bragi.version,
+            elasticsearch: details.elasticsearch.url,
+            status: details.elasticsearch.status,
+        }
+    }
+}
+
+// Deserialize the response to 'bragi/status'. Unless we're told which version of bragi
+// we're talking to, we try each known shape of the response in turn.
+fn parse_bragi_status(
+    body: &str,
+    version: Option<BragiApiVersion>,
+) -> Result<BragiStatusDetails, serde_json::Error> {
+    match version {
+        Some(BragiApiVersion::V1) => serde_json::from_str(body),
+        Some(BragiApiVersion::V2) => {
+            serde_json::from_str::<BragiStatusDetailsV2>(body).map(BragiStatusDetails::from)
+        }
+        None => serde_json::from_str(body).or_else(|err| {
+            serde_json::from_str::<BragiStatusDetailsV2>(body)
+                .map(BragiStatusDetails::from)
+                // Report the error for the original shape.
+                .map_err(|_| err)
+        }),
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct ElasticsearchInfo {
     pub label: String,
     pub url: String,
@@ -92,202 +405,2467 @@ pub struct ElasticsearchInfo {
     pub status: ServerStatus,
     pub version: String,
     pub indices: Vec<ElasticsearchIndexInfo>,
+    /// The number of indices, even when their details are omitted
+    pub indices_count: i32,
+    /// The number of indices for each place type, even when their details are omitted
+    pub index_counts_by_type: Vec<IndexTypeCount>,
     pub index_prefix: String, // eg munin
     pub updated_at: DateTime<Utc>,
+    /// True when the newest index is older than the environment's max_index_age
+    pub stale: bool,
+    /// True when elasticsearch answered, but we could not get its indices
+    pub partial: bool,
+    /// Why the probe only partially succeeded
+    pub degraded_reason: Option<String>,
+    pub warnings: Vec<String>,
+    /// The health of the cluster, when it was probed
+    pub cluster_health: Option<IndexHealth>,
+}
+
+#[juniper::graphql_object]
+impl ElasticsearchInfo {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn status(&self) -> ServerStatus {
+        self.status.clone()
+    }
+
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// The details of each index (omitted unless in verbose mode)
+    fn indices(&self) -> &[ElasticsearchIndexInfo] {
+        &self.indices
+    }
+
+    /// The number of indices, even when their details are omitted
+    fn indices_count(&self) -> i32 {
+        self.indices_count
+    }
+
+    /// The number of indices for each place type, even when their details are omitted
+    fn index_counts_by_type(&self) -> &[IndexTypeCount] {
+        &self.index_counts_by_type
+    }
+
+    fn index_prefix(&self) -> &str {
+        &self.index_prefix
+    }
+
+    fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+    }
+
+    /// True when the newest index is older than the environment's max_index_age
+    fn stale(&self) -> bool {
+        self.stale
+    }
+
+    /// True when elasticsearch answered, but we could not get its indices
+    fn partial(&self) -> bool {
+        self.partial
+    }
+
+    /// Why the probe only partially succeeded
+    fn degraded_reason(&self) -> Option<&str> {
+        self.degraded_reason.as_deref()
+    }
+
+    fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// The health of the cluster, when it was probed
+    fn cluster_health(&self) -> Option<IndexHealth> {
+        self.cluster_health.clone()
+    }
+}
+
+/// A metric exposed by bragi, with its labels if any (eg http_requests_total{code="200"})
+#[derive(Debug, Serialize, Clone, GraphQLObject)]
+pub struct BragiMetric {
+    pub name: String,
+    pub value: f64,
 }
 
+/// The number of indices sharing the same place type
 #[derive(Debug, Serialize, Clone, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexTypeCount {
+    pub place_type: String,
+    pub count: i32,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct ElasticsearchIndexInfo {
     pub label: String,
     pub place_type: String,
     pub coverage: String,
     #[serde(skip_serializing_if = "is_public")]
     pub private: PrivateStatus,
-    pub created_at: DateTime<Utc>,
-    pub count: i32,
+    pub created_at: Option<DateTime<Utc>>,
+    /// None when elasticsearch does not report it (eg for closed indices)
+    pub health: Option<IndexHealth>,
+    pub open: bool,
+    /// None when elasticsearch does not report it (eg for closed indices), or when it could not
+    /// be read
+    pub count: Option<i64>,
+    /// Change in the number of documents since the previous probe (None on the first probe)
+    pub count_delta: Option<i64>,
+    /// When the index was last written to: the probe which saw its indexing stats change, its
+    /// creation date until then, or the probe time when elasticsearch does not tell either
     pub updated_at: DateTime<Utc>,
+    /// True when updated_at is the probe time
+    pub updated_at_is_probe_time: bool,
+    /// When the index was probed
+    pub probed_at: DateTime<Utc>,
+    /// The number of segments of the index, when counted (--segment-counts)
+    pub segment_count: Option<i32>,
+}
+
+// GraphQL integers are limited to 32 bits, so document counts are exposed as floats, which
+// are exact up to 2^53.
+#[juniper::graphql_object]
+impl ElasticsearchIndexInfo {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn place_type(&self) -> &str {
+        &self.place_type
+    }
+
+    fn coverage(&self) -> &str {
+        &self.coverage
+    }
+
+    fn private(&self) -> PrivateStatus {
+        self.private.clone()
+    }
+
+    fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.created_at
+    }
+
+    /// The health of the index (null when elasticsearch does not report it, eg when closed)
+    fn health(&self) -> Option<IndexHealth> {
+        self.health.clone()
+    }
+
+    /// False when the index is closed
+    fn open(&self) -> bool {
+        self.open
+    }
+
+    /// The number of documents in the index (null when elasticsearch does not report it, eg
+    /// when closed, or when it could not be read)
+    fn count(&self) -> Option<f64> {
+        self.count.map(|count| count as f64)
+    }
+
+    /// Change in the number of documents since the previous probe (null on the first probe)
+    fn count_delta(&self) -> Option<f64> {
+        self.count_delta.map(|delta| delta as f64)
+    }
+
+    /// When the index was last written to: the probe which saw its indexing stats change, its
+    /// creation date until then, or the probe time when elasticsearch does not tell either
+    fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+    }
+
+    /// True when updatedAt is the probe time
+    fn updated_at_is_probe_time(&self) -> bool {
+        self.updated_at_is_probe_time
+    }
+
+    /// When the index was probed
+    fn probed_at(&self) -> DateTime<Utc> {
+        self.probed_at
+    }
+
+    /// The number of segments of the index, when counted (null otherwise, or when the count
+    /// failed)
+    fn segment_count(&self) -> Option<i32> {
+        self.segment_count
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ElasticsearchIndexInfoDetails {
-    pub health: String,
+    /// null for closed indices
+    pub health: Option<String>,
     pub status: String,
     pub index: String,
     #[serde(skip)]
     pub prim: u32,
     #[serde(skip)]
     pub rep: u32,
+    /// null for closed indices
     #[serde(rename = "docs.count")]
-    pub count: String,
+    pub count: Option<String>,
     #[serde(rename = "docs.deleted", skip)]
     pub deleted: String,
     #[serde(rename = "store.size", skip)]
     pub size: String,
     #[serde(rename = "pri.store.size", skip)]
     pub pri_size: String,
+    /// Creation date of the index, in milliseconds since the epoch
+    #[serde(rename = "creation.date", default)]
+    pub creation_date: Option<String>,
 }
 
 fn is_public(status: &PrivateStatus) -> bool {
     status == &PrivateStatus::Public
 }
 
+/// The order of the indices of an environment
+#[derive(Debug, Clone, Copy, Default, PartialEq, GraphQLEnum)]
+pub enum IndexSort {
+    #[default]
+    Label,
+    Count,
+    CreatedAt,
+}
+
+/// Options given by clients when listing environments
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    /// Only list the environments with these names
+    pub names: Option<Vec<String>>,
+    /// Include the details of each index
+    pub verbose: bool,
+    /// Stop listing the indices of an environment at the first one which is not green
+    pub stop_on_first_error: bool,
+    /// The order of the indices of each environment (ascending)
+    pub sort_indices_by: IndexSort,
+    /// Do not list the indices of the environments
+    pub skip_indices: bool,
+    /// Check the health of the elasticsearch clusters
+    pub cluster_health: bool,
+    /// Only list the environments whose status changed after this time
+    pub changed_since: Option<DateTime<Utc>>,
+}
+
+/// Options changing how an environment is probed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProbeOptions {
+    /// Stop listing the indices at the first one which is not green
+    pub stop_on_first_error: bool,
+    /// Do not list the indices
+    pub skip_indices: bool,
+    /// Check the health of the elasticsearch cluster
+    pub cluster_health: bool,
+}
+
+impl ProbeOptions {
+    // Whether the probe lists all the indices. The result of a probe which does not is not
+    // recorded, since it would hide the indices found by the previous one.
+    fn lists_all_indices(&self) -> bool {
+        !self.skip_indices && !self.stop_on_first_error
+    }
+}
+
+// The environments with the given names, or all of them if no names are given.
+fn select_envs(context: &Context, names: Option<Vec<String>>) -> Result<Vec<&Env>, error::Error> {
+    match names {
+        None => Ok(context.envs.values().collect()),
+        Some(names) => names
+            .into_iter()
+            .map(|name| {
+                context
+                    .envs
+                    .get(&name)
+                    .ok_or(error::Error::Environment { env: name })
+            })
+            .collect(),
+    }
+}
+
+/// Probe the environments with the given names, or all of them if no names are given.
 pub async fn list_environments(
     context: &Context,
+    options: ListOptions,
 ) -> Result<MultiEnvironmentsResponseBody, error::Error> {
-    let envs = stream::iter(context.envs.iter().map(|env| Ok(env)))
-        .try_fold(Vec::new(), |mut acc, (env, url)| async move {
-            let env = probe_environment(env, url, context).await?;
-            acc.push(env);
+    let verbose = options.verbose;
+    let sort_indices_by = options.sort_indices_by;
+    let probe_options = ProbeOptions {
+        stop_on_first_error: options.stop_on_first_error,
+        skip_indices: options.skip_indices,
+        cluster_health: options.cluster_health,
+    };
+    let changed_since = options.changed_since;
+    let envs = select_envs(context, options.names)?;
+    let envs = stream::iter(envs.into_iter().map(|env| Ok(env)))
+        .try_fold(Vec::new(), |mut acc, env| async move {
+            let env =
+                environment_info(env, context, probe_options, verbose, sort_indices_by).await?;
+            if changed_after(&env, changed_since) {
+                acc.push(env);
+            }
             Ok(acc)
         })
         .await?;
     Ok(envs.into())
 }
 
-pub async fn probe_environment<S: Into<String>>(
-    env: S,
-    url: S,
-    _context: &Context,
-) -> Result<BragiInfo, error::Error> {
-    let env = env.into();
-    let url = url.into();
-    check_accessible(env.clone(), url.clone())
-        .and_then(|(env, url)| check_bragi_status(env, url))
-        .and_then(|info| update_elasticsearch_indices(info))
-        .or_else(|_err| async { Ok(BragiInfo::new(env, url)) })
-        .await
+// Whether the status of the environment changed after the given time, if any.
+fn changed_after(info: &BragiInfo, since: Option<DateTime<Utc>>) -> bool {
+    match (since, info.status_changed_at) {
+        (None, _) => true,
+        (Some(since), Some(changed_at)) => changed_at > since,
+        (Some(_), None) => false,
+    }
 }
 
-// We retrieve all indices in json format, then use serde to deserialize into a data structure,
-// and finally parse the label to extract the information.
-pub async fn update_elasticsearch_indices(info: BragiInfo) -> Result<BragiInfo, error::Error> {
-    let es_info = info.elastic.clone();
-    let label = info.label.clone();
-    let url = info.label.clone();
-    let future = async {
-        es_info.ok_or(error::Error::MiscError {
-            msg: String::from("hello"),
+/// Probe the environments with the given names, or all of them if no names are given,
+/// concurrently. Each environment is yielded as soon as its probe completes, so the order of
+/// the environments is not specified.
+pub fn stream_environments(
+    context: Context,
+    options: ListOptions,
+) -> Result<impl Stream<Item = Result<BragiInfo, error::Error>> + Send + 'static, error::Error> {
+    let verbose = options.verbose;
+    let sort_indices_by = options.sort_indices_by;
+    let probe_options = ProbeOptions {
+        stop_on_first_error: options.stop_on_first_error,
+        skip_indices: options.skip_indices,
+        cluster_health: options.cluster_health,
+    };
+    let changed_since = options.changed_since;
+    let names: Vec<String> = select_envs(&context, options.names)?
+        .into_iter()
+        .map(|env| env.env.clone())
+        .collect();
+    let context = Arc::new(context);
+    let probes: FuturesUnordered<_> = names
+        .into_iter()
+        .map(|name| {
+            let context = context.clone();
+            async move {
+                let env = &context.envs[&name];
+                environment_info(env, &context, probe_options, verbose, sort_indices_by).await
+            }
+        })
+        .collect();
+    Ok(probes.filter(move |res| {
+        future::ready(match res {
+            Ok(info) => changed_after(info, changed_since),
+            Err(_) => true,
         })
+    }))
+}
+
+// The information about an environment, as listed: when environments are probed in the
+// background, or with --cache-mode swr, we serve their latest results, unless we are asked to
+// probe differently than they were (eg without the indices, or with the cluster health).
+async fn environment_info(
+    env: &Env,
+    context: &Context,
+    probe_options: ProbeOptions,
+    verbose: bool,
+    sort_indices_by: IndexSort,
+) -> Result<BragiInfo, error::Error> {
+    let settings = &context.settings;
+    let cached = match background::probe_interval(env, settings) {
+        _ if probe_options != ProbeOptions::default() => None,
+        Some(_) => context.results.read().unwrap().get(&env.env).cloned(),
+        None if settings.cache_mode == CacheMode::StaleWhileRevalidate => {
+            stale_while_revalidate(env, context)
+        }
+        None => None,
     };
-    future
-        .and_then(|es_info| async move { foo(es_info).await })
-        .map_ok_or_else(
-            |_err| Ok(BragiInfo::new(label, url)),
-            |es_info| {
-                Ok(BragiInfo {
-                    elastic: Some(es_info),
-                    ..info
-                })
-            },
-        )
-        .await
+    let env = match cached {
+        Some(info) => info,
+        None => probe_catching_panics(env, context, probe_options).await?,
+    };
+    let env = sort_indices(env, sort_indices_by);
+    Ok(if verbose { env } else { compact(env) })
 }
 
-async fn check_bragi_status(env: String, url: String) -> Result<BragiInfo, error::Error> {
-    let status_url = format!("{}/status", url);
-    let resp = reqwest::get(&status_url)
-        .await
-        .context(error::StatusNotAccessible { url: url.clone() })?;
-    let status: BragiStatusDetails = resp
-        .json()
-        .await
-        .context(error::StatusNotReadable { url: url.clone() })?;
-    let elastic =
-        Url::parse(&status.elasticsearch).context(error::ElasticsearchURLNotReadable {
-            url: String::from(status.elasticsearch),
-        })?;
+// The latest result of the environment, if any. When it is older than --cache-ttl, a probe is
+// started in the background to refresh it, unless one is already running, so that the next
+// queries get a fresher result. When the latest probe failed, the latest successful result is
+// returned instead, up to --max-stale seconds after it expired.
+fn stale_while_revalidate(env: &Env, context: &Context) -> Option<BragiInfo> {
+    let latest = context.results.read().unwrap().get(&env.env).cloned()?;
+    let ttl = Duration::seconds(context.settings.cache_ttl as i64);
+    let expired = Utc::now().signed_duration_since(latest.last_probed_at) > ttl;
+    if expired && context.refreshing.lock().unwrap().insert(env.env.clone()) {
+        let env = env.clone();
+        let context = context.clone();
+        tokio::spawn(async move {
+            // The probe records its result, even when it fails.
+            let _ = probe_catching_panics(&env, &context, ProbeOptions::default()).await;
+            context.refreshing.lock().unwrap().remove(&env.env);
+        });
+    }
+    if !failed(&latest) {
+        return Some(latest);
+    }
+    let max_age = ttl + Duration::seconds(context.settings.max_stale as i64);
+    let last_success = context
+        .last_successes
+        .read()
+        .unwrap()
+        .get(&env.env)
+        .cloned()
+        .filter(|success| Utc::now().signed_duration_since(success.last_probed_at) <= max_age);
+    Some(last_success.unwrap_or(latest))
+}
 
-    let elastic_url = match elastic.port() {
-        None => format!("{}://{}", elastic.scheme(), elastic.host_str().unwrap()),
-        Some(port) => format!(
-            "{}://{}:{}",
-            elastic.scheme(),
-            elastic.host_str().unwrap(),
-            port
-        ),
-    };
+// Whether the probe failed to reach bragi or its elasticsearch.
+fn failed(info: &BragiInfo) -> bool {
+    match info.status {
+        BragiStatus::BragiNotAvailable | BragiStatus::ElasticsearchNotAvailable => true,
+        BragiStatus::Available | BragiStatus::Degraded => false,
+    }
+}
 
-    let prefix = String::from(elastic.path_segments().unwrap().next().unwrap_or("munin"));
+// Sort the indices of the environment, so that the order does not change between probes.
+fn sort_indices(mut info: BragiInfo, sort: IndexSort) -> BragiInfo {
+    if let Some(es_info) = info.elastic.as_mut() {
+        match sort {
+            IndexSort::Label => es_info.indices.sort_by(|a, b| a.label.cmp(&b.label)),
+            IndexSort::Count => es_info.indices.sort_by_key(|index| index.count),
+            IndexSort::CreatedAt => es_info.indices.sort_by_key(|index| index.created_at),
+        }
+    }
+    info
+}
 
-    // We return a bragi info with empty elastic search indices... We delegate filling
-    // this information to a later stage.
-    Ok(BragiInfo {
-        label: format!("bragi_{}", env),
-        url,
-        version: status.version,
-        status: BragiStatus::Available,
-        elastic: Some(ElasticsearchInfo {
-            label: format!("elasticsearch_{}", env),
-            url: elastic_url,
-            name: String::from(""),
-            status: ServerStatus::NotAvailable,
-            version: String::from(""),
+// Remove the details of each index, keeping only their counts.
+fn compact(info: BragiInfo) -> BragiInfo {
+    BragiInfo {
+        elastic: info.elastic.map(|es_info| ElasticsearchInfo {
             indices: Vec::new(),
-            index_prefix: prefix,
-            updated_at: Utc::now(),
+            ..es_info
         }),
-        updated_at: Utc::now(),
-    })
+        ..info
+    }
 }
 
-// Check that the url is accessible (should be done with some kind of 'ping')
-// and return its arguments
-pub async fn check_accessible(env: String, url: String) -> Result<(String, String), error::Error> {
-    match reqwest::get(&url).await {
-        Ok(_) => Ok((env, url)),
-        Err(err) => Err(error::Error::NotAccessible { url, source: err }),
+/// Whether bragi answers in an environment
+#[derive(Debug, Serialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct Liveness {
+    pub label: String,
+    pub reachable: bool,
+    /// The version of bragi found by the latest full probe, if any
+    pub version: Option<String>,
+    /// Why bragi is not reachable
+    pub error: Option<String>,
+}
+
+/// Check that bragi answers in the environments with the given names, or all of them.
+pub async fn list_liveness(
+    context: &Context,
+    names: Option<Vec<String>>,
+) -> Result<Vec<Liveness>, error::Error> {
+    let envs = select_envs(context, names)?;
+    Ok(future::join_all(envs.into_iter().map(|env| probe_liveness(env, context))).await)
+}
+
+/// Only check that bragi answers, with a HEAD request on its status, which is much lighter than
+/// a full probe.
+pub async fn probe_liveness(env: &Env, context: &Context) -> Liveness {
+    let res = async {
+        check_allowed(&env.url, context).await?;
+        let (name, url) = check_accessible(env.env.clone(), env.url.clone(), context).await?;
+        let status_url = endpoint_url(&url, "status")?;
+        context
+            .request(reqwest::Method::HEAD, &name, &status_url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .context(error::StatusNotAccessible { url: status_url })?;
+        Ok::<_, error::Error>(())
+    }
+    .await;
+    let version = context
+        .results
+        .read()
+        .unwrap()
+        .get(&env.env)
+        .map(|info| info.version.clone())
+        .filter(|version| !version.is_empty());
+    Liveness {
+        label: env.env.clone(),
+        reachable: res.is_ok(),
+        version,
+        error: res.err().map(|err| format!("{}", err)),
     }
 }
 
-pub async fn foo(es_info: ElasticsearchInfo) -> Result<ElasticsearchInfo, error::Error> {
-    let indices_url = format!("{}/_cat/indices?format=json", es_info.url);
-    let indices: Option<Vec<ElasticsearchIndexInfo>> = reqwest::get(&indices_url)
-        .await
-        .context(error::NotAccessible {
-            url: indices_url.clone(),
-        })?
-        .json::<Vec<ElasticsearchIndexInfoDetails>>()
-        .await
-        .context(error::NotAccessible { url: indices_url })
-        .ok()
-        .map(|is: Vec<ElasticsearchIndexInfoDetails>| {
-            is.iter()
-                .map(|i| {
-                    let zs: Vec<&str> = i.index.split('_').collect();
-                    let (private, coverage) = if zs[2].starts_with("priv.") {
-                        (PrivateStatus::Private, zs[2].chars().skip(5).collect())
-                    } else {
-                        (PrivateStatus::Public, zs[2].to_string())
-                    };
-                    ElasticsearchIndexInfo {
-                        label: i.index.clone(),
-                        place_type: zs[1].to_string(),
+/// An environment, as configured in the running server
+#[derive(Debug, Serialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvironmentConfig {
+    pub name: String,
+    /// The url of bragi, without credentials
+    pub url: String,
+    /// Interval (in seconds) between background probes, if the environment is probed in the
+    /// background
+    pub interval: Option<i32>,
+    pub tags: Vec<String>,
+}
+
+/// The environments the server actually loaded (after expanding templates), sorted by name.
+/// Like mutations, this needs the token of --admin-token, since it tells where the environments
+/// are and how they are probed.
+pub fn list_config(context: &Context) -> Result<Vec<EnvironmentConfig>, error::Error> {
+    if !context.authorized {
+        return Err(error::Error::Unauthorized {
+            msg: String::from("reading the configuration requires the token of --admin-token"),
+        });
+    }
+    let mut envs: Vec<EnvironmentConfig> = context
+        .envs
+        .values()
+        .map(|env| EnvironmentConfig {
+            name: env.env.clone(),
+            url: redact_url(&env.url),
+            interval: background::probe_interval(env, &context.settings)
+                .map(|interval| i32::try_from(interval.as_secs()).unwrap_or(i32::MAX)),
+            tags: env.tags.clone(),
+        })
+        .collect();
+    envs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(envs)
+}
+
+/// Hide the credentials a url may contain.
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) => {
+            if parsed.password().is_some() {
+                let _ = parsed.set_password(Some("redacted"));
+            }
+            if !parsed.username().is_empty() {
+                let _ = parsed.set_username("redacted");
+            }
+            parsed.to_string()
+        }
+        // We can't tell where credentials would be in a url we can't parse.
+        Err(_) => String::from("redacted"),
+    }
+}
+
+/// An environment which is not available, and why
+#[derive(Debug, Serialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvironmentProblem {
+    pub label: String,
+    /// The team owning the environment, if any
+    pub owner: Option<String>,
+    pub status: BragiStatus,
+    pub error: Option<String>,
+}
+
+/// Probe all the environments, and only return those which are not available.
+pub async fn list_problems(context: &Context) -> Result<Vec<EnvironmentProblem>, error::Error> {
+    let envs = list_environments(context, ListOptions::default()).await?;
+    Ok(envs
+        .environments
+        .into_iter()
+        .filter(|info| info.status != BragiStatus::Available)
+        .map(|info| EnvironmentProblem {
+            label: info.label,
+            owner: info.owner,
+            status: info.status,
+            error: info.error.or(info.degraded_reason),
+        })
+        .collect())
+}
+
+/// The health of all the environments, as a single score
+#[derive(Debug, Serialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct FleetHealth {
+    /// The weighted percentage of available environments, from 0 to 100 (0 without any
+    /// environment)
+    pub score: f64,
+    /// The number of environments
+    pub total: i32,
+    /// The number of available environments
+    pub available: i32,
+    /// The weights the score is computed from
+    pub weighted: WeightedCounts,
+}
+
+/// The environments counted by their weights (see --tag-weight)
+#[derive(Debug, Serialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct WeightedCounts {
+    /// The sum of the weights of all environments
+    pub total: f64,
+    /// The sum of the weights of the available environments
+    pub available: f64,
+}
+
+/// Probe all the environments, and score their health from the fraction which are available,
+/// weighted by their tags.
+pub async fn fleet_health(context: &Context) -> Result<FleetHealth, error::Error> {
+    let envs = list_environments(context, ListOptions::default()).await?;
+    let mut health = FleetHealth {
+        score: 0.0,
+        total: 0,
+        available: 0,
+        weighted: WeightedCounts {
+            total: 0.0,
+            available: 0.0,
+        },
+    };
+    for info in envs.environments {
+        let weight = context
+            .envs
+            .get(&info.label)
+            .map(|env| env_weight(env, &context.settings.tag_weights))
+            .unwrap_or(1.0);
+        health.total += 1;
+        health.weighted.total += weight;
+        if info.status == BragiStatus::Available {
+            health.available += 1;
+            health.weighted.available += weight;
+        }
+    }
+    if health.weighted.total > 0.0 {
+        health.score = 100.0 * health.weighted.available / health.weighted.total;
+    }
+    Ok(health)
+}
+
+/// The environments sharing a tag
+#[derive(Debug, Serialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvironmentGroup {
+    /// The tag, or null for the environments without any tag
+    pub tag: Option<String>,
+    pub environments: Vec<BragiInfo>,
+}
+
+/// Probe all the environments, and group them by tag, sorted by tag and then by label. An
+/// environment with several tags is in several groups. The group of the environments without
+/// any tag comes last, even when it is empty.
+pub async fn group_environments(
+    context: &Context,
+    verbose: bool,
+) -> Result<Vec<EnvironmentGroup>, error::Error> {
+    let options = ListOptions {
+        verbose,
+        ..Default::default()
+    };
+    let mut envs = list_environments(context, options).await?.environments;
+    envs.sort_by(|a, b| a.label.cmp(&b.label));
+    let mut tagged: BTreeMap<String, Vec<BragiInfo>> = BTreeMap::new();
+    let mut untagged = Vec::new();
+    for info in envs {
+        let tags = context
+            .envs
+            .get(&info.label)
+            .map(|env| env.tags.clone())
+            .unwrap_or_default();
+        if tags.is_empty() {
+            untagged.push(info);
+            continue;
+        }
+        for tag in tags {
+            tagged.entry(tag).or_default().push(info.clone());
+        }
+    }
+    let mut groups: Vec<EnvironmentGroup> = tagged
+        .into_iter()
+        .map(|(tag, environments)| EnvironmentGroup {
+            tag: Some(tag),
+            environments,
+        })
+        .collect();
+    groups.push(EnvironmentGroup {
+        tag: None,
+        environments: untagged,
+    });
+    Ok(groups)
+}
+
+// The weight of an environment is the one of its heaviest tag, or 1 without a weighted tag.
+fn env_weight(env: &Env, weights: &[(String, f64)]) -> f64 {
+    weights
+        .iter()
+        .filter(|(tag, _)| env.tags.contains(tag))
+        .map(|(_, weight)| *weight)
+        .fold(None, |max: Option<f64>, weight| {
+            Some(max.map_or(weight, |max| max.max(weight)))
+        })
+        .unwrap_or(1.0)
+}
+
+/// Probe all the environments, and return the place types of their indices, deduplicated and
+/// sorted.
+pub async fn list_place_types(context: &Context) -> Result<Vec<String>, error::Error> {
+    let options = ListOptions {
+        verbose: true,
+        ..Default::default()
+    };
+    let envs = list_environments(context, options).await?;
+    let place_types: BTreeSet<String> = envs
+        .environments
+        .into_iter()
+        .filter_map(|info| info.elastic)
+        .flat_map(|elastic| elastic.indices)
+        .map(|index| index.place_type)
+        .collect();
+    Ok(place_types.into_iter().collect())
+}
+
+/// Probe an environment, and record the result. The probe can be cancelled by dropping it (eg
+/// when the client disconnects), which drops and so cancels the requests in flight. Nothing is
+/// recorded before all the requests are done, so a cancelled probe leaves nothing half updated.
+pub async fn probe_environment(
+    env: &Env,
+    context: &Context,
+    options: ProbeOptions,
+) -> Result<BragiInfo, error::Error> {
+    let in_flight = metrics::InFlightGuard::start();
+    let probed_at = Utc::now();
+    let mut timing = ProbeTiming::default();
+    let mut http_reachable = false;
+    let (res, total_ms) = timed(async {
+        check_allowed(&env.url, context).await?;
+        // The tunnel stays open at least until the end of the probe.
+        let tunneled;
+        let (context, _tunnel) = match &env.tunnel {
+            Some(config) => {
+                let tunnel = context.tunnel(config).await?;
+                tunneled = context.tunneled(env, &tunnel)?;
+                (&tunneled, Some(tunnel))
+            }
+            None => (context, None),
+        };
+        let (res, ms) = timed(check_accessible(env.env.clone(), env.url.clone(), context)).await;
+        timing.accessible_ms = Some(ms);
+        // A redirect is an HTTP answer too, even though the probe stops there.
+        http_reachable = match &res {
+            Ok(_) | Err(error::Error::Redirected { .. }) => true,
+            Err(_) => false,
+        };
+        let (name, url) = res?;
+        let (res, ms) = timed(check_bragi_status(name, url, probed_at, context)).await;
+        timing.status_ms = Some(ms);
+        let info = res?;
+        // Bragi tells where its elasticsearch is, which is no more trusted than a user's url.
+        if let Some(elastic) = &info.elastic {
+            check_allowed(&elastic.url, context).await?;
+        }
+        let info = match context.settings.reverse_coordinates {
+            Some(coordinates) => check_reverse(info, coordinates, context).await,
+            None => info,
+        };
+        let info = match &context.settings.search_query {
+            Some(query) => check_search(info, query, env, context).await,
+            None => info,
+        };
+        let info = if context.settings.bragi_metrics.is_empty() {
+            info
+        } else {
+            check_bragi_metrics(info, context).await
+        };
+        let info = if options.skip_indices {
+            info
+        } else {
+            let (res, ms) = timed(update_elasticsearch_indices(info, env, context, options)).await;
+            timing.indices_ms = Some(ms);
+            let info = track_writes(res?, env, context).await;
+            if context.settings.segment_counts {
+                count_segments(info, env, context).await
+            } else {
+                info
+            }
+        };
+        let info = if options.cluster_health {
+            check_cluster_health(info, env, context, options).await
+        } else {
+            info
+        };
+        Ok(check_version(info, env))
+    })
+    .await;
+    // Nothing below waits, so the probe can't be cancelled anymore.
+    in_flight.finish();
+    if let Some(threshold) = context.settings.slow_probe_threshold {
+        if u64::try_from(total_ms).unwrap_or(0) > threshold {
+            warn!(
+                context.logger,
+                "Probe of environment {} took {}ms (threshold {}ms)", env.env, total_ms, threshold
+            );
+        }
+    }
+    // The TCP check comes first, so when bragi was checked at all, only a TCP error means the
+    // connection failed.
+    let tcp_reachable = match &res {
+        _ if timing.accessible_ms.is_none() || !tcp_checked(context) => None,
+        Err(error::Error::TcpNotReachable { .. }) => Some(false),
+        _ => Some(true),
+    };
+    let info = match res {
+        Ok(info) => info,
+        Err(err) => {
+            warn!(
+                context.logger,
+                "Could not probe environment {}: {}", env.env, err
+            );
+            metrics::PROBE_ERRORS
+                .with_label_values(&[&env.env, err.code()])
+                .inc();
+            BragiInfo {
+                error: Some(format!("{}", err)),
+                ..BragiInfo::new(env.env.clone(), env.url.clone(), probed_at)
+            }
+        }
+    };
+    let info = BragiInfo {
+        owner: env.owner.clone(),
+        tcp_reachable,
+        http_reachable,
+        timing,
+        avg_probe_ms: track_latency(env, context, total_ms, options),
+        digest: digest(&info),
+        ..info
+    };
+    let previous = context
+        .results
+        .read()
+        .unwrap()
+        .get(&env.env)
+        .map(|previous| (previous.status.clone(), previous.status_changed_at));
+    let info = BragiInfo {
+        status_changed_at: match previous {
+            Some((status, _)) if status != info.status => Some(info.updated_at),
+            Some((_, changed_at)) => changed_at,
+            None => None,
+        },
+        ..info
+    };
+    if !options.lists_all_indices() {
+        return Ok(info);
+    }
+    let previous = context
+        .results
+        .write()
+        .unwrap()
+        .insert(env.env.clone(), info.clone())
+        .map(|previous| previous.status);
+    if !failed(&info) {
+        context
+            .last_successes
+            .write()
+            .unwrap()
+            .insert(env.env.clone(), info.clone());
+    }
+    if previous.as_ref() != Some(&info.status) {
+        // Nobody listening is fine.
+        let _ = context.events.send(events::StatusChange {
+            env: env.env.clone(),
+            owner: env.owner.clone(),
+            previous,
+            current: info.status.clone(),
+            at: info.updated_at,
+        });
+    }
+    Ok(info)
+}
+
+/// Probe the bragi at the given url, as an environment of its own, on a scratch copy of the
+/// context, so that neither the configured environments nor their results change.
+pub async fn probe_url(context: &Context, url: String) -> Result<BragiInfo, error::Error> {
+    if !context.authorized {
+        return Err(error::Error::Unauthorized {
+            msg: String::from("probing an arbitrary url requires the token of --admin-token"),
+        });
+    }
+    Url::parse(&url).context(error::URLNotParsable { url: url.clone() })?;
+    let env = Env {
+        env: String::from(AD_HOC_ENV),
+        url,
+        ..Default::default()
+    };
+    probe_catching_panics(&env, &context.scratch(&env)?, ProbeOptions::default()).await
+}
+
+// The name of the environments probed with probeUrl. It is the same for all of them, so that
+// they add a single series to the metrics labelled by environment.
+const AD_HOC_ENV: &str = "ad-hoc";
+
+/// Probe an environment, turning a panic into an error, so that it doesn't take down the
+/// request or the task probing the environment.
+pub async fn probe_catching_panics(
+    env: &Env,
+    context: &Context,
+    options: ProbeOptions,
+) -> Result<BragiInfo, error::Error> {
+    AssertUnwindSafe(probe_environment(env, context, options))
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|panic| {
+            Err(error::Error::Panic {
+                msg: panic_message(panic.as_ref()),
+            })
+        })
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(msg) => String::from(*msg),
+        None => panic
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| String::from("unknown cause")),
+    }
+}
+
+// The weight of the latest probe in the moving average of probe durations: the average mostly
+// reflects the last ten probes or so.
+const LATENCY_EWMA_WEIGHT: f64 = 0.2;
+
+// Fold the duration of a probe into the moving average of the environment, and return the new
+// average. Probes skipping the indices are much faster, so they are left out.
+fn track_latency(env: &Env, context: &Context, ms: i32, options: ProbeOptions) -> Option<f64> {
+    let mut latencies = context.probe_latencies.lock().unwrap();
+    if options.skip_indices {
+        return latencies.get(&env.env).copied();
+    }
+    let ms = f64::from(ms);
+    let avg = match latencies.get(&env.env) {
+        Some(avg) => avg + LATENCY_EWMA_WEIGHT * (ms - avg),
+        None => ms,
+    };
+    latencies.insert(env.env.clone(), avg);
+    metrics::PROBE_LATENCY_EWMA
+        .with_label_values(&[&env.env])
+        .set(avg);
+    Some(avg)
+}
+
+// Run the future, and return its output with the time it took, in milliseconds.
+async fn timed<F: Future>(future: F) -> (F::Output, i32) {
+    let start = Instant::now();
+    let output = future.await;
+    let ms = i32::try_from(start.elapsed().as_millis()).unwrap_or(i32::MAX);
+    (output, ms)
+}
+
+// We retrieve all indices in json format, then use serde to deserialize into a data structure,
+// and finally parse the label to extract the information.
+pub async fn update_elasticsearch_indices(
+    info: BragiInfo,
+    env: &Env,
+    context: &Context,
+    options: ProbeOptions,
+) -> Result<BragiInfo, error::Error> {
+    let es_info = info.elastic.clone();
+    let future = async {
+        es_info.ok_or(error::Error::MiscError {
+            msg: String::from("Missing elasticsearch information"),
+        })
+    };
+    let res = future
+        .and_then(|es_info| async move { foo(es_info, env, context, options).await })
+        .await;
+    match res {
+        Ok(es_info) => {
+            // A listing stopped at the first unhealthy index can't tell how many there are.
+            let missing = if options.stop_on_first_error {
+                None
+            } else {
+                missing_indices(&es_info, env)
+            };
+            let missing = missing.or_else(|| empty_indices(&es_info, env));
+            // An elasticsearch whose indices could not be listed is not healthy either.
+            let listed = es_info.status == ServerStatus::Available;
+            let status = match (&info.status, &missing) {
+                (BragiStatus::Available, Some(_)) => BragiStatus::Degraded,
+                (BragiStatus::Available, None) if !listed => BragiStatus::Degraded,
+                _ => info.status.clone(),
+            };
+            Ok(BragiInfo {
+                status,
+                partial: es_info.partial,
+                degraded_reason: es_info.degraded_reason.clone().or(missing),
+                elastic: Some(es_info),
+                ..info
+            })
+        }
+        // Bragi answered, but we could not get anything out of its elasticsearch. We still
+        // report where we tried to reach it.
+        Err(err) => {
+            let reason = format!("{}", err);
+            let elastic = info.elastic.clone().map(|es_info| ElasticsearchInfo {
+                status: ServerStatus::NotAvailable,
+                updated_at: Utc::now(),
+                degraded_reason: Some(reason.clone()),
+                ..es_info
+            });
+            Ok(BragiInfo {
+                status: BragiStatus::ElasticsearchNotAvailable,
+                partial: true,
+                degraded_reason: Some(reason),
+                elastic,
+                ..info
+            })
+        }
+    }
+}
+
+// The beginning of a body, on a single line, to show in an error message.
+const SNIPPET_CHARS: usize = 200;
+
+fn snippet(body: &str) -> String {
+    let snippet: String = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    match snippet.char_indices().nth(SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}...", &snippet[..end]),
+        None => snippet,
+    }
+}
+
+// Tell how many indices have no document, when they should degrade the environment.
+fn empty_indices(es_info: &ElasticsearchInfo, env: &Env) -> Option<String> {
+    if !(env.flag_empty_indices && env.degrade_on_empty_indices) {
+        return None;
+    }
+    let empty = es_info
+        .indices
+        .iter()
+        .filter(|index| index.count == Some(0));
+    match empty.count() {
+        0 => None,
+        empty => Some(format!("{} indices without any document", empty)),
+    }
+}
+
+// Tell how many indices are missing, when the environment serves fewer than it should.
+fn missing_indices(es_info: &ElasticsearchInfo, env: &Env) -> Option<String> {
+    let expected = env.expected_index_count?;
+    let count = usize::try_from(es_info.indices_count).unwrap_or(0);
+    if count < expected {
+        Some(format!("Only {} of {} expected indices", count, expected))
+    } else {
+        None
+    }
+}
+
+async fn check_bragi_status(
+    env: String,
+    url: String,
+    probed_at: DateTime<Utc>,
+    context: &Context,
+) -> Result<BragiInfo, error::Error> {
+    let status_url = endpoint_url(&url, "status")?;
+    let resp = context
+        .get(&env, &status_url)
+        .send()
+        .await
+        .context(error::StatusNotAccessible { url: url.clone() })?;
+    // A proxy in front of bragi may answer with an HTML error page, which is better reported as
+    // such than as a JSON error. Without a content type, we just try.
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .map(|content_type| String::from_utf8_lossy(content_type.as_bytes()).into_owned());
+    let body = read_body(resp, &status_url, context).await?;
+    if let Some(content_type) = content_type.filter(|value| !value.contains("json")) {
+        return Err(error::Error::StatusNotReadable {
+            url,
+            content_type,
+            snippet: snippet(&body),
+        });
+    }
+    let status = parse_bragi_status(&body, context.settings.bragi_api_version)
+        .context(error::StatusNotParsable { url: url.clone() })?;
+    let elastic =
+        Url::parse(&status.elasticsearch).context(error::ElasticsearchURLNotReadable {
+            url: String::from(status.elasticsearch),
+        })?;
+
+    let elastic_url = match elastic.port() {
+        None => format!("{}://{}", elastic.scheme(), elastic.host_str().unwrap()),
+        Some(port) => format!(
+            "{}://{}:{}",
+            elastic.scheme(),
+            elastic.host_str().unwrap(),
+            port
+        ),
+    };
+
+    let prefix = String::from(elastic.path_segments().unwrap().next().unwrap_or("munin"));
+
+    // We return a bragi info with empty elastic search indices... We delegate filling
+    // this information to a later stage.
+    Ok(BragiInfo {
+        label: env.clone(),
+        url,
+        owner: None,
+        version: status.version,
+        version_matches_expected: true,
+        status: BragiStatus::Available,
+        elastic: Some(ElasticsearchInfo {
+            label: env,
+            url: elastic_url,
+            name: String::from(""),
+            status: ServerStatus::NotChecked,
+            version: String::from(""),
+            indices: Vec::new(),
+            indices_count: 0,
+            index_counts_by_type: Vec::new(),
+            index_prefix: prefix,
+            updated_at: Utc::now(),
+            stale: false,
+            partial: false,
+            degraded_reason: None,
+            warnings: Vec::new(),
+            cluster_health: None,
+        }),
+        updated_at: Utc::now(),
+        last_probed_at: probed_at,
+        status_changed_at: None,
+        tcp_reachable: None,
+        http_reachable: true,
+        partial: false,
+        degraded_reason: None,
+        error: None,
+        reverse_ok: None,
+        search_ok: None,
+        search_coverage: None,
+        bragi_metrics: Vec::new(),
+        timing: ProbeTiming::default(),
+        avg_probe_ms: None,
+        digest: String::new(),
+    })
+}
+
+// Compare the version of bragi with the one expected in the environment, if any. A mismatch does
+// not fail the probe, it is reported in the warnings.
+fn check_version(info: BragiInfo, env: &Env) -> BragiInfo {
+    let expected = match &env.expected_version {
+        Some(expected) if *expected != info.version => expected,
+        _ => return info,
+    };
+    let warning = format!(
+        "Bragi version {} is not the expected version {}",
+        info.version, expected
+    );
+    BragiInfo {
+        version_matches_expected: false,
+        elastic: info.elastic.map(|es_info| {
+            let mut warnings = es_info.warnings.clone();
+            warnings.push(warning);
+            ElasticsearchInfo {
+                warnings,
+                ..es_info
+            }
+        }),
+        ..info
+    }
+}
+
+// Ask elasticsearch for the health of its cluster. A failure does not fail the probe, it is
+// reported in the warnings. When the indices were not listed, this is what tells whether
+// elasticsearch is available.
+async fn check_cluster_health(
+    info: BragiInfo,
+    env: &Env,
+    context: &Context,
+    options: ProbeOptions,
+) -> BragiInfo {
+    let es_info = match info.elastic {
+        Some(ref es_info) => es_info.clone(),
+        None => return info,
+    };
+    let res = async {
+        let health_url = endpoint_url(&es_info.url, "_cluster/health")?;
+        let resp =
+            context
+                .get(&env.env, &health_url)
+                .send()
+                .await
+                .context(error::NotAccessible {
+                    url: health_url.clone(),
+                })?;
+        let body = read_body(resp, &health_url, context).await?;
+        let health: ClusterHealth = serde_json::from_str(&body).context(error::JSONError {
+            msg: format!("Could not parse cluster health from {}", health_url),
+        })?;
+        health
+            .status
+            .parse()
+            .map_err(|msg| error::Error::MiscError { msg })
+    }
+    .await;
+    let es_info = match res {
+        Ok(health) => ElasticsearchInfo {
+            status: if options.skip_indices {
+                ServerStatus::Available
+            } else {
+                es_info.status
+            },
+            cluster_health: Some(health),
+            ..es_info
+        },
+        Err(err) => {
+            let mut warnings = es_info.warnings.clone();
+            warnings.push(format!("Could not get the cluster health: {}", err));
+            ElasticsearchInfo {
+                status: if options.skip_indices {
+                    ServerStatus::NotAvailable
+                } else {
+                    es_info.status
+                },
+                warnings,
+                ..es_info
+            }
+        }
+    };
+    BragiInfo {
+        elastic: Some(es_info),
+        ..info
+    }
+}
+
+// Count the segments of each open index, to spot those which need a force merge. Listing the
+// segments is heavy, so this is only done with --segment-counts. A failure does not fail the
+// probe, it is reported in the warnings, and the counts are left empty.
+async fn count_segments(info: BragiInfo, env: &Env, context: &Context) -> BragiInfo {
+    let es_info = match info.elastic {
+        Some(ref es_info) => es_info.clone(),
+        None => return info,
+    };
+    let res = async {
+        let url = endpoint_url(&es_info.url, "_cat/segments?format=json&h=index")?;
+        let resp = context
+            .get(&env.env, &url)
+            .send()
+            .await
+            .context(error::NotAccessible { url: url.clone() })?;
+        let body = read_body(resp, &url, context).await?;
+        let segments: Vec<Segment> = serde_json::from_str(&body).context(error::JSONError {
+            msg: format!("Could not parse segments from {}", url),
+        })?;
+        let mut counts: HashMap<String, i32> = HashMap::new();
+        for segment in segments {
+            *counts.entry(segment.index).or_insert(0) += 1;
+        }
+        Ok::<_, error::Error>(counts)
+    }
+    .await;
+    let es_info = match res {
+        // An open index without any segment has no documents yet.
+        Ok(counts) => ElasticsearchInfo {
+            indices: es_info
+                .indices
+                .into_iter()
+                .map(|index| ElasticsearchIndexInfo {
+                    segment_count: if index.open {
+                        Some(counts.get(&index.label).copied().unwrap_or(0))
+                    } else {
+                        None
+                    },
+                    ..index
+                })
+                .collect(),
+            ..es_info
+        },
+        Err(err) => {
+            let mut warnings = es_info.warnings.clone();
+            warnings.push(format!("Could not count the segments: {}", err));
+            ElasticsearchInfo {
+                warnings,
+                ..es_info
+            }
+        }
+    };
+    BragiInfo {
+        elastic: Some(es_info),
+        ..info
+    }
+}
+
+// A line of the response of _cat/segments
+#[derive(Debug, Deserialize)]
+struct Segment {
+    index: String,
+}
+
+// Elasticsearch does not tell when an index was last written to, but its indexing stats count
+// the documents indexed and deleted. When that number grows between two probes, the index was
+// written to in between, and its updatedAt becomes the time of the probe which saw it. Until
+// then, it is the creation date of the index. A failure does not fail the probe, it is reported
+// in the warnings, and the indices keep their creation date.
+async fn track_writes(info: BragiInfo, env: &Env, context: &Context) -> BragiInfo {
+    let es_info = match info.elastic {
+        Some(ref es_info) => es_info.clone(),
+        None => return info,
+    };
+    let res = async {
+        let url = endpoint_url(&es_info.url, "_stats/indexing")?;
+        let resp = context
+            .get(&env.env, &url)
+            .send()
+            .await
+            .context(error::NotAccessible { url: url.clone() })?;
+        let body = read_body(resp, &url, context).await?;
+        let stats: IndicesStats = serde_json::from_str(&body).context(error::JSONError {
+            msg: format!("Could not parse indexing stats from {}", url),
+        })?;
+        Ok::<_, error::Error>(stats)
+    }
+    .await;
+    let es_info = match res {
+        Ok(stats) => {
+            let mut writes = context.writes.lock().unwrap();
+            ElasticsearchInfo {
+                indices: es_info
+                    .indices
+                    .into_iter()
+                    .map(|index| {
+                        // Closed indices have no stats.
+                        let count = match stats.indices.get(&index.label) {
+                            Some(stats) => stats.primaries.indexing.writes(),
+                            None => return index,
+                        };
+                        let key = (env.env.clone(), index.label.clone());
+                        let written_at = match writes.get(&key) {
+                            Some((previous, _)) if count > *previous => Some(index.probed_at),
+                            // The count starts over when the shards do, which is not a write.
+                            Some((_, written_at)) => *written_at,
+                            None => None,
+                        };
+                        let known = match written_at {
+                            Some(_) => written_at,
+                            None if index.updated_at_is_probe_time => None,
+                            None => Some(index.updated_at),
+                        };
+                        writes.insert(key, (count, known));
+                        match written_at {
+                            Some(written_at) => ElasticsearchIndexInfo {
+                                updated_at: written_at,
+                                updated_at_is_probe_time: false,
+                                ..index
+                            },
+                            None => index,
+                        }
+                    })
+                    .collect(),
+                ..es_info
+            }
+        }
+        Err(err) => {
+            let mut warnings = es_info.warnings.clone();
+            warnings.push(format!(
+                "Could not get the indexing stats, indices are dated by their creation: {}",
+                err
+            ));
+            ElasticsearchInfo {
+                warnings,
+                ..es_info
+            }
+        }
+    };
+    BragiInfo {
+        elastic: Some(es_info),
+        ..info
+    }
+}
+
+// The response of _stats/indexing, by index
+#[derive(Debug, Deserialize)]
+struct IndicesStats {
+    indices: HashMap<String, IndexStats>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexStats {
+    primaries: ShardStats,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShardStats {
+    indexing: IndexingStats,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexingStats {
+    index_total: u64,
+    delete_total: u64,
+}
+
+impl IndexingStats {
+    // The number of documents indexed or deleted since the shards started.
+    fn writes(&self) -> u64 {
+        self.index_total + self.delete_total
+    }
+}
+
+// The part of the response of _cluster/health we are interested in
+#[derive(Debug, Deserialize)]
+struct ClusterHealth {
+    status: String,
+}
+
+// Ask bragi to reverse geocode the given coordinates. A failure does not fail the probe, it is
+// only recorded in reverse_ok.
+async fn check_reverse(info: BragiInfo, (lat, lon): (f64, f64), context: &Context) -> BragiInfo {
+    let res = async {
+        let reverse_url = endpoint_url(&info.url, "reverse")?;
+        let resp = context
+            .get(&info.label, &reverse_url)
+            .query(&[("lat", lat), ("lon", lon)])
+            .send()
+            .await
+            .context(error::NotAccessible { url: reverse_url })?;
+        Ok::<_, error::Error>(resp.status())
+    }
+    .await;
+    let reverse_ok = match res {
+        Ok(status) if status.is_success() => true,
+        Ok(status) => {
+            warn!(
+                context.logger,
+                "Reverse geocoding failed in environment {}: {}", info.label, status
+            );
+            false
+        }
+        Err(err) => {
+            warn!(
+                context.logger,
+                "Reverse geocoding failed in environment {}: {}", info.label, err
+            );
+            false
+        }
+    };
+    BragiInfo {
+        reverse_ok: Some(reverse_ok),
+        ..info
+    }
+}
+
+// Ask bragi to autocomplete the given text, in the coverage of the environment if any. The search
+// is only successful when bragi finds something, which tells that the data of the coverage is
+// searchable. A failure does not fail the probe, it is only recorded in search_ok.
+async fn check_search(info: BragiInfo, query: &str, env: &Env, context: &Context) -> BragiInfo {
+    let coverage = env
+        .search_coverage
+        .clone()
+        .or_else(|| context.settings.probe_query_coverage.clone());
+    let res = async {
+        let search_url = endpoint_url(&info.url, "autocomplete")?;
+        let mut params = vec![("q", query)];
+        if let Some(coverage) = &coverage {
+            params.push(("pt_dataset[]", coverage.as_str()));
+            params.push(("poi_dataset[]", coverage.as_str()));
+        }
+        let resp = context
+            .get(&info.label, &search_url)
+            .query(&params)
+            .send()
+            .await
+            .context(error::NotAccessible {
+                url: search_url.clone(),
+            })?;
+        if !resp.status().is_success() {
+            return Err(error::Error::MiscError {
+                msg: format!("{} answered {}", search_url, resp.status()),
+            });
+        }
+        let body = read_body(resp, &search_url, context).await?;
+        let results: SearchResults = serde_json::from_str(&body).context(error::JSONError {
+            msg: format!("Could not parse search results from {}", search_url),
+        })?;
+        Ok(results.features.len())
+    }
+    .await;
+    let search_ok = match res {
+        Ok(0) => {
+            warn!(
+                context.logger,
+                "Search for '{}' found nothing in environment {}", query, info.label
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(err) => {
+            warn!(
+                context.logger,
+                "Search failed in environment {}: {}", info.label, err
+            );
+            false
+        }
+    };
+    BragiInfo {
+        search_ok: Some(search_ok),
+        search_coverage: coverage,
+        ..info
+    }
+}
+
+// The part of the response of /autocomplete we are interested in
+#[derive(Debug, Deserialize)]
+struct SearchResults {
+    features: Vec<serde_json::Value>,
+}
+
+// Read the metrics selected with --bragi-metric from bragi's metrics endpoint. Older versions of
+// bragi don't have one, so a 404 is expected, and a failure does not fail the probe.
+async fn check_bragi_metrics(info: BragiInfo, context: &Context) -> BragiInfo {
+    let settings = &context.settings;
+    let res = async {
+        let metrics_url = endpoint_url(&info.url, &settings.bragi_metrics_path)?;
+        let resp = context
+            .get(&info.label, &metrics_url)
+            .send()
+            .await
+            .context(error::NotAccessible {
+                url: metrics_url.clone(),
+            })?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            debug!(
+                context.logger,
+                "Environment {} does not expose metrics at {}", info.label, metrics_url
+            );
+            return Ok(Vec::new());
+        }
+        if !resp.status().is_success() {
+            return Err(error::Error::MiscError {
+                msg: format!("{} answered {}", metrics_url, resp.status()),
+            });
+        }
+        let body = read_body(resp, &metrics_url, context).await?;
+        Ok(parse_metrics(&body, &settings.bragi_metrics))
+    }
+    .await;
+    let bragi_metrics = match res {
+        Ok(metrics) => metrics,
+        Err(err) => {
+            warn!(
+                context.logger,
+                "Could not read the metrics of environment {}: {}", info.label, err
+            );
+            Vec::new()
+        }
+    };
+    BragiInfo {
+        bragi_metrics,
+        ..info
+    }
+}
+
+// Pick the samples of the given metrics in the prometheus text format. A sample is a line
+// 'name{labels} value [timestamp]', where the labels are optional and may contain spaces.
+fn parse_metrics(body: &str, names: &[String]) -> Vec<BragiMetric> {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, rest) = match line.rfind('}') {
+                Some(end) => line.split_at(end + 1),
+                None => line.split_at(line.find(char::is_whitespace)?),
+            };
+            let name = series.split('{').next()?;
+            if !names.iter().any(|wanted| wanted == name) {
+                return None;
+            }
+            let value = rest.split_whitespace().next()?.parse().ok()?;
+            Some(BragiMetric {
+                name: series.to_string(),
+                value,
+            })
+        })
+        .collect()
+}
+
+// Build the url of an endpoint below a base url, keeping the path of the base url, so that
+// environments behind a path based gateway can be probed (eg https://gateway/tenants/42 and
+// status give https://gateway/tenants/42/status).
+fn endpoint_url(base: &str, endpoint: &str) -> Result<String, error::Error> {
+    let mut url = Url::parse(base).context(error::URLNotParsable { url: base })?;
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url.join(endpoint)
+        .map(|url| url.to_string())
+        .context(error::URLNotParsable { url: base })
+}
+
+// Read the body of a response, giving up as soon as it is larger than --max-response-bytes,
+// so that a misbehaving upstream can't make us buffer an arbitrary amount of data.
+async fn read_body(
+    mut resp: reqwest::Response,
+    url: &str,
+    context: &Context,
+) -> Result<String, error::Error> {
+    let limit = context.settings.max_response_bytes;
+    let too_large = |size: u64| limit.map(|limit| size > limit).unwrap_or(false);
+    if resp.content_length().map(too_large).unwrap_or(false) {
+        return Err(error::Error::ResponseTooLarge {
+            url: String::from(url),
+            limit: limit.unwrap_or(0),
+        });
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .context(error::BodyNotReadable { url })?
+    {
+        body.extend_from_slice(&chunk);
+        if too_large(body.len() as u64) {
+            return Err(error::Error::ResponseTooLarge {
+                url: String::from(url),
+                limit: limit.unwrap_or(0),
+            });
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+// How long the TCP check waits for the connection
+const TCP_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Whether the TCP check can be trusted: through a SOCKS proxy, we could reach the proxy but not
+// the host, so we don't check.
+fn tcp_checked(context: &Context) -> bool {
+    context.settings.socks_proxy.is_none()
+}
+
+// Make sure the url does not target a host or an address denied by --deny-cidr. The host is
+// resolved (or its address taken from --resolve) first. A host which does not resolve is let
+// through, since the request fails anyway, unless it goes through a proxy resolving it.
+async fn check_allowed(url: &str, context: &Context) -> Result<(), error::Error> {
+    let deny = &context.settings.deny;
+    if deny.is_empty() {
+        return Ok(());
+    }
+    let parsed = Url::parse(url).context(error::URLNotParsable { url })?;
+    let denied = |target: String| error::Error::Denied {
+        url: String::from(url),
+        target,
+    };
+    let addrs: Vec<std::net::IpAddr> = match parsed.host() {
+        Some(url::Host::Ipv4(addr)) => vec![addr.into()],
+        Some(url::Host::Ipv6(addr)) => vec![addr.into()],
+        Some(url::Host::Domain(host)) => {
+            if deny.iter().any(|rule| rule.denies_host(host)) {
+                return Err(denied(String::from(host)));
+            }
+            match context.settings.resolved(&parsed) {
+                Some(addr) => vec![addr],
+                None => {
+                    let port = parsed.port_or_known_default().unwrap_or(80);
+                    tokio::net::lookup_host((host, port))
+                        .await
+                        .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+                        .unwrap_or_default()
+                }
+            }
+        }
+        None => Vec::new(),
+    };
+    match addrs
+        .into_iter()
+        .find(|addr| deny.iter().any(|rule| rule.denies_addr(*addr)))
+    {
+        Some(addr) => Err(denied(addr.to_string())),
+        None => Ok(()),
+    }
+}
+
+// Open a TCP connection to the host of the url (or its address from --resolve), to tell network
+// problems from application ones. The connection counts as a request in --request-budget.
+async fn check_tcp(url: &str, context: &Context) -> Result<(), error::Error> {
+    let parsed = Url::parse(url).context(error::URLNotParsable { url })?;
+    let port = match parsed.port_or_known_default() {
+        Some(port) => port,
+        // Without a port, the HTTP request fails with a better error.
+        None => return Ok(()),
+    };
+    // An IPv6 host is bracketed in the url, so addresses are taken from the parsed host.
+    let (domain, addr) = match parsed.host() {
+        Some(url::Host::Ipv4(addr)) => (None, Some(std::net::IpAddr::from(addr))),
+        Some(url::Host::Ipv6(addr)) => (None, Some(std::net::IpAddr::from(addr))),
+        Some(url::Host::Domain(host)) => (Some(host), context.settings.resolved(&parsed)),
+        // Without a host, the HTTP request fails with a better error.
+        None => return Ok(()),
+    };
+    context.outbound_requests.fetch_add(1, Ordering::Relaxed);
+    let connect = async {
+        match (addr, domain) {
+            (Some(addr), _) => tokio::net::TcpStream::connect((addr, port)).await,
+            (None, domain) => {
+                tokio::net::TcpStream::connect((domain.unwrap_or_default(), port)).await
+            }
+        }
+    };
+    match tokio::time::timeout(TCP_CONNECT_TIMEOUT, connect).await {
+        Ok(Ok(_)) => Ok(()),
+        _ => Err(error::Error::TcpNotReachable {
+            url: String::from(url),
+        }),
+    }
+}
+
+// How long we wait before retrying a request which could not connect
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+// Take a retry from the budget of the current probe cycle (--retry-budget), if any is left.
+fn take_retry(context: &Context) -> bool {
+    if context.settings.retry_budget.is_none() {
+        return true;
+    }
+    context
+        .retry_tokens
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+            tokens.checked_sub(1)
+        })
+        .is_ok()
+}
+
+// Check that the url is accessible (should be done with some kind of 'ping')
+// and return its arguments. A request which could not connect or timed out is retried up to
+// --probe-retries times, as long as the retry budget of the cycle lasts.
+pub async fn check_accessible(
+    env: String,
+    url: String,
+    context: &Context,
+) -> Result<(String, String), error::Error> {
+    if tcp_checked(context) {
+        check_tcp(&url, context).await?;
+    }
+    let mut retries = 0;
+    let res = loop {
+        match context.get(&env, &url).send().await {
+            Err(err)
+                if (err.is_connect() || err.is_timeout())
+                    && retries < context.settings.probe_retries =>
+            {
+                if !take_retry(context) {
+                    warn!(
+                        context.logger,
+                        "Retry budget of this probe cycle is spent, not retrying {}", url
+                    );
+                    break Err(err);
+                }
+                retries += 1;
+                tokio::time::delay_for(RETRY_DELAY).await;
+            }
+            res => break res,
+        }
+    };
+    match res {
+        // Redirects are only returned when we don't follow them (--max-redirects 0).
+        Ok(resp) if resp.status().is_redirection() => Err(error::Error::Redirected {
+            status: resp.status().as_u16(),
+            location: resp
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .map(String::from)
+                .unwrap_or_default(),
+            url,
+        }),
+        Ok(_) => Ok((env, url)),
+        Err(err) => match &context.settings.socks_proxy {
+            // When going through a proxy, a connection error means we could not reach the proxy.
+            Some(proxy) if err.is_connect() => Err(error::Error::ProxyNotAccessible {
+                url,
+                proxy: proxy.clone(),
+                source: err,
+            }),
+            _ => Err(error::Error::NotAccessible { url, source: err }),
+        },
+    }
+}
+
+pub async fn foo(
+    es_info: ElasticsearchInfo,
+    env: &Env,
+    context: &Context,
+    options: ProbeOptions,
+) -> Result<ElasticsearchInfo, error::Error> {
+    let settings = &context.settings;
+    let indices_url = endpoint_url(
+        &es_info.url,
+        "_cat/indices?format=json&h=health,status,index,docs.count,creation.date",
+    )?;
+    let resp = context
+        .get(&env.env, &indices_url)
+        .send()
+        .await
+        .context(error::NotAccessible {
+            url: indices_url.clone(),
+        })?;
+    let body = read_body(resp, &indices_url, context).await;
+    // We distinguish an elasticsearch we could not read from, and one which answered something
+    // we could not make sense of.
+    let details = match body {
+        Ok(body) => match serde_json::from_str::<Vec<ElasticsearchIndexInfoDetails>>(&body) {
+            Ok(details) => Ok(details),
+            Err(err) => {
+                debug!(
+                    context.logger,
+                    "Unexpected indices response from {}: {}",
+                    indices_url,
+                    body.chars().take(200).collect::<String>()
+                );
+                Err((
+                    ServerStatus::NotReadable,
+                    format!("Could not parse indices from {}: {}", indices_url, err),
+                ))
+            }
+        },
+        Err(err) => Err((ServerStatus::NotAvailable, format!("{}", err))),
+    };
+    let details = details.map(|details| exclude_indices(details, env));
+    // When we only want to know if an index is unhealthy, we don't go past the first one.
+    let (details, unhealthy) = match details {
+        Ok(details) if options.stop_on_first_error => first_unhealthy(details),
+        details => (details, None),
+    };
+    let delimiter = env.index_delimiter.unwrap_or('_');
+    let mut naming_warnings = Vec::new();
+    let indices: Option<Vec<ElasticsearchIndexInfo>> =
+        details.as_ref().ok().map(|is: &Vec<ElasticsearchIndexInfoDetails>| {
+            is.iter()
+                .filter_map(|i| {
+                    let name = match parse_index_name(&i.index, delimiter) {
+                        Some(name) => name,
+                        None => {
+                            naming_warnings.push(format!(
+                                "Index {} is not named with segments separated by '{}'",
+                                i.index, delimiter
+                            ));
+                            return None;
+                        }
+                    };
+                    let coverage = if settings.normalize_coverage {
+                        normalize_coverage(name.coverage)
+                    } else {
+                        name.coverage.to_string()
+                    };
+                    let probed_at = Utc::now();
+                    let written_at = i.creation_date.as_deref().and_then(parse_epoch_millis);
+                    Some(ElasticsearchIndexInfo {
+                        label: i.index.clone(),
+                        place_type: name.place_type.to_string(),
                         coverage,
-                        private,
-                        created_at: DateTime::<Utc>::from_utc(
-                            NaiveDateTime::new(
-                                NaiveDate::parse_from_str(zs[3], "%Y%m%d")
-                                    .unwrap_or(NaiveDate::from_ymd(1970, 1, 1)),
-                                NaiveTime::parse_from_str(zs[4], "%H%M%S")
-                                    .unwrap_or(NaiveTime::from_hms(0, 1, 1)),
-                            ),
-                            Utc,
+                        private: name.private,
+                        created_at: parse_created_at(
+                            name.date,
+                            name.time,
+                            env.date_format.as_ref().unwrap_or(&settings.date_format),
+                            env.time_format.as_ref().unwrap_or(&settings.time_format),
                         ),
-                        count: i.count.parse().unwrap_or(0),
-                        updated_at: Utc::now(),
-                    }
+                        health: i.health.as_deref().and_then(|health| health.parse().ok()),
+                        open: i.status == "open",
+                        count: i.count.as_deref().and_then(parse_count),
+                        count_delta: None,
+                        updated_at: written_at.unwrap_or(probed_at),
+                        updated_at_is_probe_time: written_at.is_none(),
+                        probed_at,
+                        segment_count: None,
+                    })
                 })
                 .collect()
         });
-    let status = if indices.is_some() {
-        ServerStatus::Available
-    } else {
-        ServerStatus::NotAvailable
+    let count_warnings = details
+        .as_ref()
+        .map(|is| unparsable_counts(is.as_slice()))
+        .unwrap_or_default();
+    let (status, degraded_reason) = match details {
+        Ok(_) => (ServerStatus::Available, None),
+        Err((status, reason)) => (status, Some(reason)),
+    };
+    let degraded_reason = degraded_reason.or(unhealthy);
+    let indices = indices.unwrap_or(Vec::new());
+    let indices = match &env.coverage_allowlist {
+        Some(allowed) if !allowed.is_empty() => {
+            let allowed = normalized_coverages(allowed, settings);
+            indices
+                .into_iter()
+                .filter(|index| allowed.contains(&index.coverage))
+                .collect()
+        }
+        _ => indices,
+    };
+    for count in indices.iter().filter_map(|index| index.count) {
+        metrics::INDEX_DOCUMENT_COUNT.observe(count as f64);
+    }
+    let mut indices = indices;
+    let mut warnings = match &env.known_coverages {
+        Some(known) => unknown_coverages(&indices, known, settings),
+        None => Vec::new(),
+    };
+    warnings.extend(naming_warnings);
+    warnings.extend(count_warnings);
+    // Only the probes whose results are recorded tell the counts of the next one.
+    if options.lists_all_indices() {
+        warnings.extend(track_count_deltas(&mut indices, env, context));
+    }
+    if env.flag_empty_indices {
+        warnings.extend(
+            indices
+                .iter()
+                .filter(|index| index.count == Some(0))
+                .map(|index| format!("Index {} has no document", index.label)),
+        );
+    }
+    let stale = match (env.max_index_age, newest_index(&indices)) {
+        (Some(max_age), Some((label, created_at))) => {
+            let age = Utc::now().signed_duration_since(created_at);
+            if age > Duration::seconds(max_age) {
+                warnings.push(format!(
+                    "Newest index {} was created {} hours ago",
+                    label,
+                    age.num_hours()
+                ));
+                true
+            } else {
+                false
+            }
+        }
+        // Without a threshold or without any dated index, we can't tell if the indices are stale.
+        _ => false,
     };
     Ok(ElasticsearchInfo {
         status,
-        indices: indices.unwrap_or(Vec::new()),
+        indices_count: i32::try_from(indices.len()).unwrap(),
+        index_counts_by_type: index_counts_by_type(&indices),
+        indices,
         updated_at: Utc::now(),
+        stale,
+        partial: degraded_reason.is_some(),
+        degraded_reason,
+        warnings,
         ..es_info
     })
 }
+
+// Count the indices of each place type, sorted by place type.
+fn index_counts_by_type(indices: &[ElasticsearchIndexInfo]) -> Vec<IndexTypeCount> {
+    let mut counts: BTreeMap<&str, i32> = BTreeMap::new();
+    for index in indices.iter() {
+        *counts.entry(&index.place_type).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .map(|(place_type, count)| IndexTypeCount {
+            place_type: String::from(place_type),
+            count,
+        })
+        .collect()
+}
+
+// Hash the meaningful fields of a probe result. Timestamps, durations and warnings change at each
+// probe, so they are left out, as well as count deltas which follow from the counts.
+fn digest(info: &BragiInfo) -> String {
+    let mut digest = Digest::new();
+    digest.write(&info.label);
+    digest.write(&format!("{:?}", info.status));
+    digest.write(&info.version);
+    digest.write(info.error.as_deref().unwrap_or(""));
+    digest.write(&format!(
+        "{} {:?} {:?}",
+        info.partial, info.reverse_ok, info.search_ok
+    ));
+    if let Some(elastic) = &info.elastic {
+        digest.write(&format!("{:?}", elastic.status));
+        digest.write(&elastic.name);
+        digest.write(&elastic.version);
+        digest.write(&format!("{} {}", elastic.indices_count, elastic.stale));
+        let mut indices: Vec<&ElasticsearchIndexInfo> = elastic.indices.iter().collect();
+        indices.sort_by(|a, b| a.label.cmp(&b.label));
+        for index in indices {
+            digest.write(&index.label);
+            let count = index.count.map(|count| count.to_string());
+            digest.write(&format!(
+                "{} {:?} {}",
+                count.unwrap_or_default(),
+                index.health,
+                index.open
+            ));
+        }
+    }
+    digest.finish()
+}
+
+// FNV-1a, which unlike the hashers of the standard library gives the same digests across
+// versions of the service.
+struct Digest(u64);
+
+impl Digest {
+    fn new() -> Self {
+        Digest(0xcbf2_9ce4_8422_2325)
+    }
+
+    // Each field is terminated, so that moving characters between fields changes the digest.
+    fn write(&mut self, field: &str) {
+        for byte in field.bytes().chain(std::iter::once(0)) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+// The segments of an index name, eg munin_addr_priv.fr_20200101_120000.
+struct IndexName<'a> {
+    place_type: &'a str,
+    coverage: &'a str,
+    private: PrivateStatus,
+    date: Option<&'a str>,
+    time: Option<&'a str>,
+}
+
+// Split an index name into its segments: a prefix, the place type, the coverage (prefixed with
+// 'priv.' when private), and optionally the date and time of creation. Returns None when there
+// is no place type or coverage.
+fn parse_index_name(name: &str, delimiter: char) -> Option<IndexName> {
+    let mut segments = name.split(delimiter).skip(1);
+    let place_type = segments.next().filter(|segment| !segment.is_empty())?;
+    let coverage = segments.next().filter(|segment| !segment.is_empty())?;
+    let (private, coverage) = match coverage.strip_prefix("priv.") {
+        Some(coverage) => (PrivateStatus::Private, coverage),
+        None => (PrivateStatus::Public, coverage),
+    };
+    Some(IndexName {
+        place_type,
+        coverage,
+        private,
+        date: segments.next(),
+        time: segments.next(),
+    })
+}
+
+// Drop the indices matching one of the exclude patterns of the environment. Patterns are
+// checked when the configuration is loaded, so invalid ones can be ignored here.
+fn exclude_indices(
+    details: Vec<ElasticsearchIndexInfoDetails>,
+    env: &Env,
+) -> Vec<ElasticsearchIndexInfoDetails> {
+    let patterns: Vec<Pattern> = env
+        .index_exclude_patterns
+        .iter()
+        .filter_map(|pattern| Pattern::new(pattern).ok())
+        .collect();
+    details
+        .into_iter()
+        .filter(|details| {
+            !patterns
+                .iter()
+                .any(|pattern| pattern.matches(&details.index))
+        })
+        .collect()
+}
+
+// Keep the indices up to the first one which is not green, and return why it is unhealthy.
+fn first_unhealthy(
+    mut details: Vec<ElasticsearchIndexInfoDetails>,
+) -> (
+    Result<Vec<ElasticsearchIndexInfoDetails>, (ServerStatus, String)>,
+    Option<String>,
+) {
+    match details
+        .iter()
+        .position(|details| details.health.as_deref() != Some("green"))
+    {
+        Some(position) => {
+            // A closed index has no health, but its status tells why.
+            let unhealthy = &details[position];
+            let health = unhealthy.health.as_deref().unwrap_or(&unhealthy.status);
+            let reason = format!("Index {} is {}", unhealthy.index, health);
+            details.truncate(position + 1);
+            (Ok(details), Some(reason))
+        }
+        None => (Ok(details), None),
+    }
+}
+
+// Parse a date in milliseconds since the epoch, as elasticsearch reports the creation of indices.
+fn parse_epoch_millis(millis: &str) -> Option<DateTime<Utc>> {
+    let millis: i64 = millis.parse().ok()?;
+    let nanos = (millis.rem_euclid(1000) * 1_000_000) as u32;
+    Utc.timestamp_opt(millis.div_euclid(1000), nanos).single()
+}
+
+// Returns the label and creation date of the most recent index, ignoring indices
+// without a creation date.
+fn newest_index(indices: &[ElasticsearchIndexInfo]) -> Option<(&str, DateTime<Utc>)> {
+    indices
+        .iter()
+        .filter_map(|index| {
+            index
+                .created_at
+                .map(|created_at| (index.label.as_str(), created_at))
+        })
+        .max_by_key(|(_, created_at)| *created_at)
+}
+
+// The creation date of an index is extracted from the date and time segments of its name.
+// If any of them is missing, or does not match the expected format, we don't know when the index
+// was created.
+fn parse_created_at(
+    date: Option<&str>,
+    time: Option<&str>,
+    date_format: &str,
+    time_format: &str,
+) -> Option<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(date?, date_format).ok()?;
+    let time = NaiveTime::parse_from_str(time?, time_format).ok()?;
+    Some(DateTime::<Utc>::from_utc(
+        NaiveDateTime::new(date, time),
+        Utc,
+    ))
+}
+
+// Compare the number of documents in each index with the one from the previous probe, and
+// remember the new one for the next probe. Returns a warning for each index which lost more than
+// --count-drop-warning percent of its documents.
+fn track_count_deltas(
+    indices: &mut [ElasticsearchIndexInfo],
+    env: &Env,
+    context: &Context,
+) -> Vec<String> {
+    let threshold = i64::from(context.settings.count_drop_warning);
+    let mut counts = context.counts.lock().unwrap();
+    let mut warnings = Vec::new();
+    for index in indices.iter_mut() {
+        // An index whose count is unknown keeps the count of the last probe which knew it.
+        let count = match index.count {
+            Some(count) => count,
+            None => continue,
+        };
+        let key = (env.env.clone(), index.label.clone());
+        if let Some(previous) = counts.insert(key, count) {
+            let delta = count - previous;
+            index.count_delta = Some(delta);
+            if previous > 0 && -delta * 100 >= threshold * previous {
+                warnings.push(format!(
+                    "Index {} went from {} to {} documents",
+                    index.label, previous, count
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+// Returns a warning for each index whose number of documents is not a number, even abbreviated.
+// These indices are reported without a number of documents, as closed indices are.
+fn unparsable_counts(details: &[ElasticsearchIndexInfoDetails]) -> Vec<String> {
+    details
+        .iter()
+        .filter_map(|details| {
+            let count = details.count.as_deref()?;
+            match parse_count(count) {
+                Some(_) => None,
+                None => Some(format!(
+                    "Could not read the number of documents of index {}: '{}'",
+                    details.index, count
+                )),
+            }
+        })
+        .collect()
+}
+
+// Parse a number of documents, either plain (eg 1500) or abbreviated with a suffix, as some
+// versions of elasticsearch do in _cat (eg 1.5k, 15k, 1.2m or 3b).
+fn parse_count(count: &str) -> Option<i64> {
+    let count = count.trim();
+    if let Ok(count) = count.parse() {
+        return Some(count);
+    }
+    let suffix = count.chars().last()?;
+    let number = &count[..count.len() - suffix.len_utf8()];
+    let multiplier = match suffix.to_ascii_lowercase() {
+        'k' => 1e3,
+        'm' => 1e6,
+        'b' | 'g' => 1e9,
+        _ => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    if !number.is_finite() || number < 0.0 {
+        return None;
+    }
+    Some((number * multiplier).round() as i64)
+}
+
+fn normalize_coverage(coverage: &str) -> String {
+    coverage.trim().to_lowercase()
+}
+
+// Coverages from the configuration go through the same normalization as those extracted
+// from index names, so that they can be compared.
+fn normalized_coverages(coverages: &[String], settings: &Settings) -> Vec<String> {
+    coverages
+        .iter()
+        .map(|coverage| {
+            if settings.normalize_coverage {
+                normalize_coverage(coverage)
+            } else {
+                coverage.clone()
+            }
+        })
+        .collect()
+}
+
+// Returns a warning for each index whose coverage is not in the list of known coverages.
+fn unknown_coverages(
+    indices: &[ElasticsearchIndexInfo],
+    known: &[String],
+    settings: &Settings,
+) -> Vec<String> {
+    let known = normalized_coverages(known, settings);
+    indices
+        .iter()
+        .filter(|index| !known.contains(&index.coverage))
+        .map(|index| {
+            format!(
+                "Unknown coverage {} in index {}",
+                index.coverage, index.label
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::testing::{context, settings};
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn check_accessible_reports_redirects() {
+        let redirect = warp::any().map(|| {
+            warp::reply::with_header(
+                warp::reply::with_status("", warp::http::StatusCode::MOVED_PERMANENTLY),
+                "location",
+                "http://bragi.example.com/",
+            )
+        });
+        let (addr, server) = warp::serve(redirect).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let context = context(Settings {
+            max_redirects: Some(0),
+            ..settings()
+        });
+        let url = format!("http://{}/", addr);
+        match check_accessible(String::from("test"), url.clone(), &context).await {
+            Err(error::Error::Redirected {
+                url: redirected,
+                status,
+                location,
+            }) => {
+                assert_eq!(redirected, url);
+                assert_eq!(status, 301);
+                assert_eq!(location, "http://bragi.example.com/");
+            }
+            res => panic!("expected a redirect, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parse_count_reads_plain_counts() {
+        assert_eq!(parse_count("1500"), Some(1500));
+        assert_eq!(parse_count(" 1500 "), Some(1500));
+    }
+
+    #[test]
+    fn parse_count_reads_abbreviated_counts() {
+        assert_eq!(parse_count("1.5k"), Some(1500));
+        assert_eq!(parse_count("15K"), Some(15_000));
+        assert_eq!(parse_count("1.2m"), Some(1_200_000));
+    }
+
+    #[test]
+    fn parse_count_rejects_garbage() {
+        assert_eq!(parse_count("lots"), None);
+        assert_eq!(parse_count("1.5x"), None);
+        assert_eq!(parse_count("-2k"), None);
+        assert_eq!(parse_count(""), None);
+    }
+
+    #[test]
+    fn closed_indices_are_read_without_health_nor_count() {
+        let body = r#"[
+            {"health": "green", "status": "open", "index": "munin_addr_fr", "docs.count": "1500"},
+            {"health": null, "status": "close", "index": "munin_poi_fr", "docs.count": null}
+        ]"#;
+        let details: Vec<ElasticsearchIndexInfoDetails> = serde_json::from_str(body).unwrap();
+        assert_eq!(details[0].health.as_deref(), Some("green"));
+        assert_eq!(details[0].count.as_deref(), Some("1500"));
+        assert_eq!(details[1].status, "close");
+        assert!(details[1].health.is_none());
+        assert!(details[1].count.is_none());
+        // A closed index is not an unreadable count.
+        assert!(unparsable_counts(&details).is_empty());
+    }
+
+    #[test]
+    fn parse_count_reads_counts_above_i32() {
+        assert_eq!(parse_count("3000000000"), Some(3_000_000_000));
+        assert_eq!(parse_count("3b"), Some(3_000_000_000));
+    }
+
+    const BRAGI_STATUS_V1: &str = include_str!("../../tests/fixtures/bragi_status_v1.json");
+    const BRAGI_STATUS_V2: &str = include_str!("../../tests/fixtures/bragi_status_v2.json");
+
+    #[test]
+    fn parse_bragi_status_reads_both_shapes() {
+        let v1 = parse_bragi_status(BRAGI_STATUS_V1, None).unwrap();
+        assert_eq!(v1.version, "v1.14.0");
+        assert_eq!(v1.elasticsearch, "http://localhost:9200/munin");
+        assert_eq!(v1.status, "good");
+        let v2 = parse_bragi_status(BRAGI_STATUS_V2, None).unwrap();
+        assert_eq!(v2.version, "v2.3.1");
+        assert_eq!(v2.elasticsearch, "http://localhost:9200");
+        assert_eq!(v2.status, "available");
+    }
+
+    #[test]
+    fn parse_bragi_status_reads_the_given_shape_only() {
+        assert!(parse_bragi_status(BRAGI_STATUS_V1, Some(BragiApiVersion::V1)).is_ok());
+        assert!(parse_bragi_status(BRAGI_STATUS_V1, Some(BragiApiVersion::V2)).is_err());
+        assert!(parse_bragi_status(BRAGI_STATUS_V2, Some(BragiApiVersion::V2)).is_ok());
+        assert!(parse_bragi_status(BRAGI_STATUS_V2, Some(BragiApiVersion::V1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn dropped_probe_stops_before_recording_its_result() {
+        // A bragi which accepts connections, but never answers.
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+        let env = Env {
+            env: String::from("hung"),
+            url: format!("http://{}/", addr),
+            ..Default::default()
+        };
+        let context = context(settings());
+        let cancelled = metrics::PROBES_CANCELLED.get();
+        // The probe is dropped when the timeout is over, as it is when its client disconnects.
+        let probe = probe_environment(&env, &context, Default::default());
+        let timeout = std::time::Duration::from_millis(200);
+        assert!(tokio::time::timeout(timeout, probe).await.is_err());
+        assert!(context.results.read().unwrap().get("hung").is_none());
+        // Other tests may drop probes at the same time, so only a lower bound holds.
+        assert!(metrics::PROBES_CANCELLED.get() > cancelled);
+    }
+
+    #[test]
+    fn endpoint_url_keeps_the_path_of_the_base() {
+        assert_eq!(
+            endpoint_url("http://h/prefix", "status").unwrap(),
+            "http://h/prefix/status"
+        );
+        assert_eq!(
+            endpoint_url("http://h/prefix/", "status").unwrap(),
+            "http://h/prefix/status"
+        );
+        assert_eq!(
+            endpoint_url("http://h", "status").unwrap(),
+            "http://h/status"
+        );
+    }
+
+    #[test]
+    fn parse_created_at_reads_the_default_formats() {
+        let created_at = parse_created_at(Some("20200512"), Some("093000"), "%Y%m%d", "%H%M%S");
+        assert_eq!(created_at, Some(Utc.ymd(2020, 5, 12).and_hms(9, 30, 0)));
+    }
+
+    #[test]
+    fn parse_created_at_reads_the_given_formats() {
+        let created_at = parse_created_at(Some("2020.05.12"), Some("0930"), "%Y.%m.%d", "%H%M");
+        assert_eq!(created_at, Some(Utc.ymd(2020, 5, 12).and_hms(9, 30, 0)));
+        // Names following the default formats no longer match.
+        let created_at = parse_created_at(Some("20200512"), Some("0930"), "%Y.%m.%d", "%H%M");
+        assert_eq!(created_at, None);
+    }
+
+    #[test]
+    fn parse_created_at_needs_both_segments() {
+        assert!(parse_created_at(Some("20200512"), None, "%Y%m%d", "%H%M%S").is_none());
+        assert!(parse_created_at(None, Some("093000"), "%Y%m%d", "%H%M%S").is_none());
+    }
+
+    fn index(count: i64) -> ElasticsearchIndexInfo {
+        let now = Utc::now();
+        ElasticsearchIndexInfo {
+            label: String::from("munin_addr_fr_20200512_093000"),
+            place_type: String::from("addr"),
+            coverage: String::from("fr"),
+            private: PrivateStatus::Public,
+            created_at: None,
+            health: None,
+            open: true,
+            count: Some(count),
+            count_delta: None,
+            updated_at: now,
+            updated_at_is_probe_time: true,
+            probed_at: now,
+            segment_count: None,
+        }
+    }
+
+    struct IndexQuery;
+
+    #[juniper::graphql_object]
+    impl IndexQuery {
+        fn index() -> ElasticsearchIndexInfo {
+            index(3_000_000_000)
+        }
+    }
+
+    #[tokio::test]
+    async fn count_above_i32_is_exposed_as_a_float() {
+        let schema = juniper::RootNode::new(
+            IndexQuery,
+            juniper::EmptyMutation::<()>::new(),
+            juniper::EmptySubscription::<()>::new(),
+        );
+        let (value, errors) = juniper::execute(
+            "{ index { count } }",
+            None,
+            &schema,
+            &juniper::Variables::new(),
+            &(),
+        )
+        .await
+        .unwrap();
+        assert!(errors.is_empty());
+        let count = value
+            .as_object_value()
+            .and_then(|query| query.get_field_value("index"))
+            .and_then(|index| index.as_object_value())
+            .and_then(|index| index.get_field_value("count"))
+            .and_then(|count| count.as_float_value());
+        assert_eq!(count, Some(3e9));
+    }
+}
@@ -0,0 +1,60 @@
+use chrono::prelude::*;
+use slog::{info, warn, Logger};
+use tokio::sync::broadcast::{self, RecvError};
+
+use super::environment::BragiStatus;
+use crate::metrics;
+
+/// The number of status changes kept for the consumers. When a consumer falls behind by more
+/// than that, it misses the oldest changes.
+pub const CAPACITY: usize = 1024;
+
+/// A change in the status of an environment, found by a probe
+#[derive(Debug, Clone)]
+pub struct StatusChange {
+    pub env: String,
+    /// The team owning the environment, if any
+    pub owner: Option<String>,
+    /// None on the first probe of the environment
+    pub previous: Option<BragiStatus>,
+    pub current: BragiStatus,
+    pub at: DateTime<Utc>,
+}
+
+/// Create the channel on which probes publish status changes. Probes never wait for the
+/// consumers: a slow consumer loses the oldest changes instead, with a warning.
+pub fn channel() -> broadcast::Sender<StatusChange> {
+    let (sender, _) = broadcast::channel(CAPACITY);
+    sender
+}
+
+/// Log each status change, and count it in besp_status_changes_total.
+pub async fn record_status_changes(
+    mut receiver: broadcast::Receiver<StatusChange>,
+    logger: Logger,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(change) => {
+                info!(
+                    logger,
+                    "Environment {} went from {:?} to {:?}",
+                    change.env,
+                    change.previous,
+                    change.current;
+                    "owner" => change.owner.as_deref().unwrap_or("")
+                );
+                metrics::STATUS_CHANGES
+                    .with_label_values(&[&change.env, &format!("{:?}", change.current)])
+                    .inc();
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    logger,
+                    "Dropped {} status changes, recording is too slow", missed
+                );
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
@@ -1,13 +1,328 @@
-use juniper::{EmptyMutation, EmptySubscription, FieldResult, IntoFieldError, RootNode};
-use slog::Logger;
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use futures::stream::{Stream, StreamExt};
+use juniper::{DefaultScalarValue, FieldError, FieldResult, IntoFieldError, RootNode};
+use slog::{o, warn, Logger};
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use url::Url;
 
 use super::environment;
+use super::events;
+use crate::error;
+use crate::metrics;
+use crate::settings::{DenyRule, Env, Settings, TunnelConfig};
+use crate::tunnel::Tunnel;
 
 #[derive(Debug, Clone)]
 pub struct Context {
     pub logger: Logger,
-    pub envs: HashMap<String, String>,
+    /// The environments, as configured when this context was created
+    pub envs: HashMap<String, Env>,
+    /// The environments as currently configured, which change when the configuration is
+    /// reloaded
+    pub config: Arc<RwLock<HashMap<String, Env>>>,
+    pub settings: Arc<Settings>,
+    pub client: reqwest::Client,
+    /// The client used for environments accepting invalid certificates, if there are any
+    pub insecure_client: Option<reqwest::Client>,
+    /// The latest probe results, by environment
+    pub results: Arc<RwLock<HashMap<String, environment::BragiInfo>>>,
+    /// The latest successful probe results, by environment, served for a while when probes
+    /// fail (--max-stale)
+    pub last_successes: Arc<RwLock<HashMap<String, environment::BragiInfo>>>,
+    /// The number of documents found in each index (by environment and index label)
+    /// during the previous probe
+    pub counts: Arc<Mutex<HashMap<(String, String), i64>>>,
+    /// The number of writes (indexing and deletions) elasticsearch reported for each index (by
+    /// environment and index label) during the previous probe, with when the index was last
+    /// written to, if known
+    pub writes: Arc<Mutex<HashMap<(String, String), (u64, Option<DateTime<Utc>>)>>>,
+    /// Where probes publish the changes of status of environments
+    pub events: tokio::sync::broadcast::Sender<events::StatusChange>,
+    /// The number of requests sent to the environments
+    pub outbound_requests: Arc<AtomicU64>,
+    /// Moving average of the duration (in milliseconds) of the probes, by environment
+    pub probe_latencies: Arc<Mutex<HashMap<String, f64>>>,
+    /// The environments being refreshed in the background (--cache-mode swr)
+    pub refreshing: Arc<Mutex<HashSet<String>>>,
+    /// The retries left in the current probe cycle (--retry-budget)
+    pub retry_tokens: Arc<AtomicU64>,
+    /// When the background probe loop last showed it was running
+    pub last_loop_tick: Arc<Mutex<Instant>>,
+    /// The SSH tunnels kept open between probes, by bastion
+    pub tunnels: Arc<Mutex<HashMap<TunnelConfig, Arc<Tunnel>>>>,
+    /// Whether the request presented the token of --admin-token
+    pub authorized: bool,
+}
+
+impl Context {
+    /// Create a context with an empty probe history.
+    pub fn new(
+        logger: Logger,
+        envs: HashMap<String, Env>,
+        settings: Settings,
+    ) -> Result<Self, error::Error> {
+        let client = settings.client(false)?;
+        if settings.danger_accept_invalid_certs {
+            warn!(
+                logger,
+                "TLS certificates are NOT verified for any environment (--danger-accept-invalid-certs)"
+            );
+        }
+        let insecure: Vec<&str> = envs
+            .values()
+            .filter(|env| env.insecure)
+            .map(|env| env.env.as_str())
+            .collect();
+        let insecure_client = if insecure.is_empty() {
+            None
+        } else {
+            warn!(
+                logger,
+                "TLS certificates are NOT verified for environments {}",
+                insecure.join(", ")
+            );
+            Some(settings.client(true)?)
+        };
+        let unresolved: Vec<&str> = envs
+            .values()
+            .filter(|env| resolve_ignored(env, &settings))
+            .map(|env| env.env.as_str())
+            .collect();
+        if !unresolved.is_empty() {
+            warn!(
+                logger,
+                "--resolve does not apply to HTTPS environments {}, which are resolved by DNS",
+                unresolved.join(", ")
+            );
+        }
+        Ok(Context {
+            logger,
+            config: Arc::new(RwLock::new(envs.clone())),
+            envs,
+            settings: Arc::new(settings),
+            client,
+            insecure_client,
+            results: Arc::new(RwLock::new(HashMap::new())),
+            last_successes: Arc::new(RwLock::new(HashMap::new())),
+            counts: Arc::new(Mutex::new(HashMap::new())),
+            writes: Arc::new(Mutex::new(HashMap::new())),
+            events: events::channel(),
+            outbound_requests: Arc::new(AtomicU64::new(0)),
+            probe_latencies: Arc::new(Mutex::new(HashMap::new())),
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
+            retry_tokens: Arc::new(AtomicU64::new(settings.retry_budget.unwrap_or(0))),
+            // The loop starts with the server, so it gets its first interval to tick.
+            last_loop_tick: Arc::new(Mutex::new(Instant::now())),
+            tunnels: Arc::new(Mutex::new(HashMap::new())),
+            authorized: false,
+        })
+    }
+
+    /// A copy of the context for an incoming request: its log lines carry the request id, and
+    /// it is authorized when the Authorization header holds the token of --admin-token.
+    pub fn for_request(&self, request_id: Option<String>, authorization: Option<String>) -> Self {
+        let authorized = match (&self.settings.admin_token, authorization) {
+            (Some(token), Some(authorization)) => {
+                constant_time_eq(&authorization, &format!("Bearer {}", token))
+            }
+            _ => false,
+        };
+        match request_id {
+            Some(id) => Context {
+                logger: self.logger.new(o!("request_id" => id)),
+                authorized,
+                ..self.snapshot()
+            },
+            None => Context {
+                authorized,
+                ..self.snapshot()
+            },
+        }
+    }
+
+    /// A copy of the context with only the given environment, and an empty probe history, so
+    /// that probing the environment leaves no trace in the original context. The url of the
+    /// environment comes from a client, so the loopback and link-local ranges are denied on top
+    /// of --deny-cidr.
+    pub fn scratch(&self, env: &Env) -> Result<Self, error::Error> {
+        let mut envs = HashMap::new();
+        envs.insert(env.env.clone(), env.clone());
+        let mut deny = self.settings.deny.clone();
+        deny.extend(DenyRule::defaults());
+        let settings = Settings {
+            deny,
+            ..(*self.settings).clone()
+        };
+        Ok(Context {
+            client: settings.client(false)?,
+            insecure_client: None,
+            settings: Arc::new(settings),
+            config: Arc::new(RwLock::new(envs.clone())),
+            envs,
+            results: Arc::new(RwLock::new(HashMap::new())),
+            last_successes: Arc::new(RwLock::new(HashMap::new())),
+            counts: Arc::new(Mutex::new(HashMap::new())),
+            writes: Arc::new(Mutex::new(HashMap::new())),
+            events: events::channel(),
+            probe_latencies: Arc::new(Mutex::new(HashMap::new())),
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
+            tunnels: Arc::new(Mutex::new(HashMap::new())),
+            ..self.clone()
+        })
+    }
+
+    /// The tunnel through the given bastion: the one opened by a previous probe while it is
+    /// still open, or a new one.
+    pub async fn tunnel(&self, config: &TunnelConfig) -> Result<Arc<Tunnel>, error::Error> {
+        let open = self
+            .tunnels
+            .lock()
+            .unwrap()
+            .get(config)
+            .filter(|tunnel| tunnel.is_open())
+            .cloned();
+        if let Some(tunnel) = open {
+            return Ok(tunnel);
+        }
+        let tunnel = Arc::new(Tunnel::open(config).await?);
+        self.tunnels
+            .lock()
+            .unwrap()
+            .insert(config.clone(), tunnel.clone());
+        Ok(tunnel)
+    }
+
+    /// A copy of the context probing the given environment through its SSH tunnel: the tunnel
+    /// is the proxy of the client.
+    pub fn tunneled(&self, env: &Env, tunnel: &Tunnel) -> Result<Self, error::Error> {
+        let settings = Settings {
+            socks_proxy: Some(tunnel.proxy()),
+            ..(*self.settings).clone()
+        };
+        Ok(Context {
+            client: settings.client(env.insecure)?,
+            insecure_client: None,
+            settings: Arc::new(settings),
+            ..self.clone()
+        })
+    }
+
+    /// A copy of the context with the environments as currently configured.
+    pub fn snapshot(&self) -> Self {
+        Context {
+            envs: self.config.read().unwrap().clone(),
+            ..self.clone()
+        }
+    }
+
+    /// Replace the configured environments, and forget what we know about those which were
+    /// removed, closing the tunnels through bastions they no longer use.
+    pub fn reconfigure(&self, envs: HashMap<String, Env>) {
+        self.tunnels.lock().unwrap().retain(|bastion, _| {
+            envs.values()
+                .any(|env| env.tunnel.as_ref() == Some(bastion))
+        });
+        let removed: Vec<String> = {
+            let mut config = self.config.write().unwrap();
+            let removed = config
+                .keys()
+                .filter(|name| !envs.contains_key(*name))
+                .cloned()
+                .collect();
+            *config = envs;
+            removed
+        };
+        for env in removed {
+            self.results.write().unwrap().remove(&env);
+            self.last_successes.write().unwrap().remove(&env);
+            self.counts
+                .lock()
+                .unwrap()
+                .retain(|(counted, _), _| *counted != env);
+            self.writes
+                .lock()
+                .unwrap()
+                .retain(|(written, _), _| *written != env);
+            self.probe_latencies.lock().unwrap().remove(&env);
+            let _ = metrics::PROBE_LATENCY_EWMA.remove_label_values(&[&env]);
+        }
+    }
+
+    /// The client used to probe the given environment.
+    pub fn client_for(&self, env: &str) -> &reqwest::Client {
+        match (&self.insecure_client, self.envs.get(env)) {
+            (Some(client), Some(env)) if env.insecure => client,
+            _ => &self.client,
+        }
+    }
+
+    /// Start a GET request probing the given environment.
+    pub fn get(&self, env: &str, url: &str) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::GET, env, url)
+    }
+
+    /// Start a request probing the given environment. When the host of the url is overridden
+    /// with --resolve, the request is sent to the given address instead, with the original
+    /// Host header.
+    pub fn request(
+        &self,
+        method: reqwest::Method,
+        env: &str,
+        url: &str,
+    ) -> reqwest::RequestBuilder {
+        let client = self.client_for(env);
+        self.outbound_requests.fetch_add(1, Ordering::Relaxed);
+        match resolve(url, &self.settings) {
+            Some((url, host)) => client
+                .request(method, url)
+                .header(reqwest::header::HOST, host),
+            None => client.request(method, url),
+        }
+    }
+}
+
+// Replace the host of the url by its address from --resolve, if any, and return it with the
+// value of the Host header.
+fn resolve(url: &str, settings: &Settings) -> Option<(Url, String)> {
+    let mut url = Url::parse(url).ok()?;
+    let addr = settings.resolved(&url)?;
+    let host = String::from(url.host_str()?);
+    let header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    };
+    url.set_ip_host(addr).ok()?;
+    Some((url, header))
+}
+
+// Whether the environment is served over HTTPS by a host given to --resolve, which then does not
+// apply to it.
+fn resolve_ignored(env: &Env, settings: &Settings) -> bool {
+    match Url::parse(&env.url) {
+        Ok(url) => {
+            let host = url.host_str();
+            url.scheme() == "https"
+                && settings
+                    .resolve
+                    .iter()
+                    .any(|(name, _)| host == Some(name.as_str()))
+        }
+        Err(_) => false,
+    }
+}
+
+// Compare the strings in a time which only depends on their length, so that the admin token
+// can't be guessed from how long it takes to refuse a request.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let diff = a
+        .bytes()
+        .zip(b.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    a.len() == b.len() && diff == 0
 }
 
 impl juniper::Context for Context {}
@@ -18,19 +333,161 @@ pub struct Query;
     Context = Context
 )]
 impl Query {
-    /// Return a list of all environments
+    /// Return a list of all environments, or only those with the given names.
+    /// Index details are omitted with verbose false. With stopOnFirstError, the indices of
+    /// an environment are listed up to the first one which is not green, and the environment is
+    /// reported as degraded. Indices are sorted by label, unless sortIndicesBy says otherwise.
+    /// With includeIndices false, the indices are not listed at all, and with
+    /// includeClusterHealth, the health of each elasticsearch cluster is checked. With
+    /// changedSince, only the environments whose status changed after that time are returned.
     async fn environments(
         &self,
         context: &Context,
+        names: Option<Vec<String>>,
+        verbose: Option<bool>,
+        stop_on_first_error: Option<bool>,
+        sort_indices_by: Option<environment::IndexSort>,
+        include_indices: Option<bool>,
+        include_cluster_health: Option<bool>,
+        changed_since: Option<DateTime<Utc>>,
     ) -> FieldResult<environment::MultiEnvironmentsResponseBody> {
-        environment::list_environments(context)
+        let options = environment::ListOptions {
+            names,
+            verbose: verbose.unwrap_or(true),
+            stop_on_first_error: stop_on_first_error.unwrap_or(false),
+            sort_indices_by: sort_indices_by.unwrap_or_default(),
+            skip_indices: !include_indices.unwrap_or(true),
+            cluster_health: include_cluster_health.unwrap_or(false),
+            changed_since,
+        };
+        environment::list_environments(context, options)
+            .await
+            .map_err(IntoFieldError::into_field_error)
+    }
+
+    /// Check that bragi answers in all environments, or only those with the given names,
+    /// without listing the elasticsearch indices.
+    async fn liveness(
+        &self,
+        context: &Context,
+        names: Option<Vec<String>>,
+    ) -> FieldResult<Vec<environment::Liveness>> {
+        environment::list_liveness(context, names)
+            .await
+            .map_err(IntoFieldError::into_field_error)
+    }
+
+    /// Return the environments as configured in the running server, with credentials removed.
+    /// Only allowed with the token of --admin-token.
+    fn config(&self, context: &Context) -> FieldResult<Vec<environment::EnvironmentConfig>> {
+        environment::list_config(context).map_err(IntoFieldError::into_field_error)
+    }
+
+    /// Return the environments which are not available, with the reason
+    async fn problems(
+        &self,
+        context: &Context,
+    ) -> FieldResult<Vec<environment::EnvironmentProblem>> {
+        environment::list_problems(context)
+            .await
+            .map_err(IntoFieldError::into_field_error)
+    }
+
+    /// Return the place types of the indices of all environments, deduplicated and sorted
+    async fn place_types(&self, context: &Context) -> FieldResult<Vec<String>> {
+        environment::list_place_types(context)
+            .await
+            .map_err(IntoFieldError::into_field_error)
+    }
+
+    /// Probe all environments, and score their health from 0 to 100, from the fraction which
+    /// are available, weighted by their tags (see --tag-weight)
+    async fn fleet_health(&self, context: &Context) -> FieldResult<environment::FleetHealth> {
+        environment::fleet_health(context)
+            .await
+            .map_err(IntoFieldError::into_field_error)
+    }
+
+    /// Probe all environments, and group them by tag. An environment with several tags is in
+    /// each of their groups, and those without any tag are in the last group, with a null tag.
+    /// Index details are omitted with verbose false.
+    async fn grouped_environments(
+        &self,
+        context: &Context,
+        verbose: Option<bool>,
+    ) -> FieldResult<Vec<environment::EnvironmentGroup>> {
+        environment::group_environments(context, verbose.unwrap_or(true))
             .await
             .map_err(IntoFieldError::into_field_error)
     }
 }
 
-type Schema = RootNode<'static, Query, EmptyMutation<Context>, EmptySubscription<Context>>;
+pub struct Mutation;
+
+#[juniper::graphql_object(
+    Context = Context
+)]
+impl Mutation {
+    /// Probe the bragi at the given url, which does not need to be configured. Nothing is
+    /// recorded. Only allowed with the token of --admin-token, since the server then sends
+    /// requests wherever it is told.
+    async fn probe_url(
+        &self,
+        context: &Context,
+        url: String,
+    ) -> FieldResult<environment::BragiInfo> {
+        environment::probe_url(context, url)
+            .await
+            .map_err(IntoFieldError::into_field_error)
+    }
+}
+
+type EnvironmentsStream = Pin<
+    Box<dyn Stream<Item = Result<environment::MultiEnvironmentsResponseBody, FieldError>> + Send>,
+>;
+
+pub struct Subscription;
+
+#[juniper::graphql_subscription(
+    Context = Context
+)]
+impl Subscription {
+    /// Probe all environments every `interval` seconds (60 by default), and push the results
+    async fn environments(context: &Context, interval: Option<i32>) -> EnvironmentsStream {
+        let context = context.clone();
+        let interval = Duration::from_secs(interval.unwrap_or(60).max(1) as u64);
+        // Each probe picks up the environments as currently configured.
+        let stream = tokio::time::interval(interval).then(move |_| {
+            let context = context.snapshot();
+            async move {
+                let options = environment::ListOptions {
+                    verbose: true,
+                    ..Default::default()
+                };
+                environment::list_environments(&context, options)
+                    .await
+                    .map_err(IntoFieldError::into_field_error)
+            }
+        });
+        Box::pin(stream)
+    }
+}
+
+type Schema = RootNode<'static, Query, Mutation, Subscription>;
+
+pub type Coordinator = juniper_subscriptions::Coordinator<
+    'static,
+    Query,
+    Mutation,
+    Subscription,
+    Context,
+    DefaultScalarValue,
+>;
 
 pub fn schema() -> Schema {
-    Schema::new(Query, EmptyMutation::new(), EmptySubscription::new())
+    Schema::new(Query, Mutation, Subscription)
+}
+
+pub fn coordinator() -> Coordinator {
+    Coordinator::new(schema())
 }
@@ -0,0 +1,173 @@
+use chrono::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use warp::reply::{self, Reply, Response};
+use warp::Rejection;
+
+use super::environment::{BragiInfo, BragiStatus};
+use super::gql::Context;
+
+// The series available for each environment: 1 when it is available, 0 otherwise, and its number
+// of indices.
+const SERIES: [&str; 2] = ["available", "indices"];
+
+/// The body of POST /grafana/search
+#[derive(Debug, Deserialize)]
+pub struct SearchRequest {
+    /// What the user typed so far
+    #[serde(default)]
+    pub target: String,
+}
+
+/// The body of POST /grafana/query
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    pub range: TimeRange,
+    pub targets: Vec<QueryTarget>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimeRange {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryTarget {
+    /// 'env:series' for a time series, or 'series' for a table of all environments
+    pub target: String,
+    /// 'timeserie' (sic) or 'table'
+    #[serde(rename = "type", default)]
+    pub kind: Option<String>,
+}
+
+/// The body of POST /grafana/annotations
+#[derive(Debug, Deserialize)]
+pub struct AnnotationsRequest {
+    pub range: TimeRange,
+    /// The annotation query, which is echoed in each annotation
+    pub annotation: Value,
+}
+
+/// List the targets matching what the user typed: each series of each environment, as
+/// 'env:series', and each series alone, for tables of all environments.
+pub async fn search(request: SearchRequest, context: Context) -> Result<Response, Rejection> {
+    let mut names: Vec<&String> = context.envs.keys().collect();
+    names.sort();
+    let targets: Vec<String> = names
+        .into_iter()
+        .flat_map(|name| {
+            SERIES
+                .iter()
+                .map(move |series| format!("{}:{}", name, series))
+        })
+        .chain(SERIES.iter().map(|series| String::from(*series)))
+        .filter(|target| target.contains(&request.target))
+        .collect();
+    Ok(reply::json(&targets).into_response())
+}
+
+/// Return the latest probe result of each target, as a time series or a table. There is no
+/// history: a time series has at most one point, the latest probe, when it falls in the range.
+pub async fn query(request: QueryRequest, context: Context) -> Result<Response, Rejection> {
+    let results = latest_results(&context);
+    let responses: Vec<Value> = request
+        .targets
+        .iter()
+        .filter_map(|target| match target.kind.as_deref() {
+            Some("table") => table(&target.target, &results),
+            _ => time_series(&target.target, &results, &request.range),
+        })
+        .collect();
+    Ok(reply::json(&responses).into_response())
+}
+
+/// Annotate the environments which are not available, at the time of their latest probe.
+pub async fn annotations(
+    request: AnnotationsRequest,
+    context: Context,
+) -> Result<Response, Rejection> {
+    let annotations: Vec<Value> = latest_results(&context)
+        .values()
+        .filter(|info| info.status != BragiStatus::Available)
+        .filter(|info| in_range(info, &request.range))
+        .map(|info| {
+            json!({
+                "annotation": request.annotation,
+                "time": info.last_probed_at.timestamp_millis(),
+                "title": format!("{} is {:?}", info.label, info.status),
+                "text": info.error.as_deref().or_else(|| info.degraded_reason.as_deref()),
+                "tags": [info.label],
+            })
+        })
+        .collect();
+    Ok(reply::json(&annotations).into_response())
+}
+
+// The latest probe result of each configured environment, sorted by name. Grafana polls often,
+// so nothing is probed here.
+fn latest_results(context: &Context) -> BTreeMap<String, BragiInfo> {
+    let results = context.results.read().unwrap();
+    context
+        .envs
+        .keys()
+        .filter_map(|name| Some((name.clone(), results.get(name)?.clone())))
+        .collect()
+}
+
+fn time_series(
+    target: &str,
+    results: &BTreeMap<String, BragiInfo>,
+    range: &TimeRange,
+) -> Option<Value> {
+    let (env, series) = target.rsplit_once(':')?;
+    let info = results.get(env)?;
+    let datapoints: Vec<(f64, i64)> = value(info, series)
+        .filter(|_| in_range(info, range))
+        .map(|value| (value, info.last_probed_at.timestamp_millis()))
+        .into_iter()
+        .collect();
+    Some(json!({ "target": target, "datapoints": datapoints }))
+}
+
+fn table(series: &str, results: &BTreeMap<String, BragiInfo>) -> Option<Value> {
+    if !SERIES.contains(&series) {
+        return None;
+    }
+    let rows: Vec<Value> = results
+        .values()
+        .map(|info| {
+            json!([
+                info.label,
+                info.last_probed_at.timestamp_millis(),
+                value(info, series)
+            ])
+        })
+        .collect();
+    Some(json!({
+        "type": "table",
+        "columns": [
+            { "text": "Environment", "type": "string" },
+            { "text": "Time", "type": "time" },
+            { "text": series, "type": "number" },
+        ],
+        "rows": rows,
+    }))
+}
+
+fn value(info: &BragiInfo, series: &str) -> Option<f64> {
+    match series {
+        "available" if info.status == BragiStatus::Available => Some(1.0),
+        "available" => Some(0.0),
+        "indices" => info
+            .elastic
+            .as_ref()
+            .map(|elastic| f64::from(elastic.indices_count)),
+        _ => None,
+    }
+}
+
+fn in_range(info: &BragiInfo, range: &TimeRange) -> bool {
+    range.from <= info.last_probed_at && info.last_probed_at <= range.to
+}
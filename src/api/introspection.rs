@@ -0,0 +1,147 @@
+use hyper::body::Bytes;
+use juniper::http::{GraphQLBatchRequest, GraphQLRequest, GraphQLResponse};
+use juniper::parser::{Lexer, Spanning, Token};
+use juniper::{graphql_value, FieldError, InputValue};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use warp::filters::BoxedFilter;
+use warp::{http, Filter, Rejection};
+
+use super::gql::{self, Context};
+
+/// The GraphQL endpoint, like juniper_warp's, but answering queries on the schema (`__schema`
+/// or `__type`) with an error, so that the schema can't be enumerated (--disable-introspection).
+/// `__typename` is still allowed: it only names the type of an object, and clients rely on it.
+pub fn graphql_filter(state: BoxedFilter<(Context,)>) -> BoxedFilter<(http::Response<Vec<u8>>,)> {
+    let schema = Arc::new(gql::schema());
+    let get = warp::get()
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|mut params: HashMap<String, String>| async move {
+            let query = params.remove("query").ok_or_else(warp::reject)?;
+            let variables = match params.remove("variables") {
+                Some(variables) => Some(
+                    serde_json::from_str::<InputValue>(&variables).map_err(|_| warp::reject())?,
+                ),
+                None => None,
+            };
+            Ok::<_, Rejection>(Batch::Single(Request {
+                query,
+                operation_name: params.remove("operation_name"),
+                variables,
+            }))
+        });
+    let post_json = warp::post()
+        .and(warp::header::exact_ignore_case(
+            "content-type",
+            "application/json",
+        ))
+        .and(warp::body::json());
+    let post_graphql = warp::post()
+        .and(warp::header::exact_ignore_case(
+            "content-type",
+            "application/graphql",
+        ))
+        .and(warp::body::bytes())
+        .and_then(|body: Bytes| async move {
+            let query = String::from_utf8(body.to_vec()).map_err(|_| warp::reject())?;
+            Ok::<_, Rejection>(Batch::Single(Request {
+                query,
+                operation_name: None,
+                variables: None,
+            }))
+        });
+    get.or(post_json)
+        .unify()
+        .or(post_graphql)
+        .unify()
+        .and(state)
+        .and_then(move |batch: Batch, context: Context| {
+            let schema = schema.clone();
+            async move {
+                if batch.queries().any(is_introspection) {
+                    let error = FieldError::new(
+                        "Introspection is disabled",
+                        graphql_value!({ "code": "IntrospectionDisabled" }),
+                    );
+                    return Ok::<_, Rejection>(response(&GraphQLResponse::error(error), false));
+                }
+                let res = batch.into_request().execute(&schema, &context).await;
+                Ok(response(&res, res.is_ok()))
+            }
+        })
+        .boxed()
+}
+
+// A GraphQL request, read before juniper's so that we can look at its query, which juniper keeps
+// private.
+#[derive(Debug, Deserialize)]
+struct Request {
+    query: String,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    variables: Option<InputValue>,
+}
+
+// A single request, or a batch of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Batch {
+    Single(Request),
+    Batch(Vec<Request>),
+}
+
+impl Batch {
+    // The query of each request of the batch.
+    fn queries(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        match self {
+            Batch::Single(request) => Box::new(std::iter::once(request.query.as_str())),
+            Batch::Batch(requests) => {
+                Box::new(requests.iter().map(|request| request.query.as_str()))
+            }
+        }
+    }
+
+    fn into_request(self) -> GraphQLBatchRequest {
+        let convert = |request: Request| {
+            GraphQLRequest::new(request.query, request.operation_name, request.variables)
+        };
+        match self {
+            Batch::Single(request) => GraphQLBatchRequest::Single(convert(request)),
+            Batch::Batch(requests) => {
+                GraphQLBatchRequest::Batch(requests.into_iter().map(convert).collect())
+            }
+        }
+    }
+}
+
+/// Whether the query selects `__schema` or `__type`. The query is only tokenized, so that these
+/// names are not mistaken for strings or comments.
+pub fn is_introspection(query: &str) -> bool {
+    for token in Lexer::new(query) {
+        match token {
+            Ok(Spanning {
+                item: Token::Name(name),
+                ..
+            }) if name == "__schema" || name == "__type" => return true,
+            Ok(_) => {}
+            // The lexer does not move past an error, and juniper rejects the query anyway.
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+// A JSON response, with the status juniper_warp uses.
+fn response<T: Serialize>(body: &T, ok: bool) -> http::Response<Vec<u8>> {
+    let status = if ok {
+        http::StatusCode::OK
+    } else {
+        http::StatusCode::BAD_REQUEST
+    };
+    http::Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(serde_json::to_vec(body).expect("GraphQL responses can be serialized"))
+        .expect("the response is valid")
+}
@@ -1,2 +1,9 @@
+pub mod background;
 pub mod environment;
+pub mod events;
 pub mod gql;
+pub mod grafana;
+pub mod introspection;
+pub mod rest;
+#[cfg(test)]
+mod testing;
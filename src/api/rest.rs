@@ -0,0 +1,132 @@
+use futures::stream::StreamExt;
+use hyper::Body;
+use serde::Deserialize;
+use std::convert::Infallible;
+use warp::http::{self, StatusCode};
+use warp::reply::Response;
+use warp::Rejection;
+
+use super::environment;
+use super::gql::Context;
+
+/// The query string of GET /environments
+#[derive(Debug, Deserialize)]
+pub struct EnvironmentsQuery {
+    /// Comma separated names of the environments to list (all by default)
+    pub names: Option<String>,
+    /// Include the details of each index (true by default)
+    pub verbose: Option<bool>,
+}
+
+/// The media type of JSON Lines responses
+const NDJSON: &str = "application/x-ndjson";
+
+/// List the environments as JSON. The response carries a weak ETag computed from the digests of
+/// the environments, and a request with a matching If-None-Match gets a 304 without a body.
+/// When the client accepts application/x-ndjson, the environments are streamed instead, one per
+/// line, as their probes complete.
+pub async fn environments(
+    query: EnvironmentsQuery,
+    if_none_match: Option<String>,
+    accept: Option<String>,
+    context: Context,
+) -> Result<Response, Rejection> {
+    let options = environment::ListOptions {
+        names: query
+            .names
+            .map(|names| names.split(',').map(String::from).collect()),
+        verbose: query.verbose.unwrap_or(true),
+        ..Default::default()
+    };
+    if accept
+        .map(|accept| accepts_ndjson(&accept))
+        .unwrap_or(false)
+    {
+        return Ok(stream_environments(context, options));
+    }
+    let (body, etag) = match environment::list_environments(&context, options).await {
+        Ok(envs) => (
+            serde_json::to_vec(&envs).expect("environments are serializable"),
+            etag(&envs.digest),
+        ),
+        Err(err) => {
+            let body = serde_json::json!({ "error": format!("{}", err) });
+            return Ok(json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                body.to_string(),
+            ));
+        }
+    };
+    if if_none_match
+        .map(|tags| matches_etag(&tags, &etag))
+        .unwrap_or(false)
+    {
+        return Ok(http::Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header("etag", etag)
+            .body(Body::empty())
+            .expect("response is valid"));
+    }
+    let mut resp = json_response(StatusCode::OK, body);
+    resp.headers_mut()
+        .insert("etag", etag.parse().expect("etag is a valid header"));
+    Ok(resp)
+}
+
+// Stream the environments as JSON Lines. Once the stream has started, the status can no longer
+// change, so an environment whose probe fails is reported as a line with an error.
+fn stream_environments(context: Context, options: environment::ListOptions) -> Response {
+    let envs = match environment::stream_environments(context, options) {
+        Ok(envs) => envs,
+        Err(err) => {
+            let body = serde_json::json!({ "error": format!("{}", err) });
+            return json_response(StatusCode::INTERNAL_SERVER_ERROR, body.to_string());
+        }
+    };
+    let lines = envs.map(|env| {
+        let mut line = match env {
+            Ok(env) => serde_json::to_vec(&env).expect("environments are serializable"),
+            Err(err) => serde_json::json!({ "error": format!("{}", err) })
+                .to_string()
+                .into_bytes(),
+        };
+        line.push(b'\n');
+        Ok::<_, Infallible>(line)
+    });
+    http::Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", NDJSON)
+        .body(Body::wrap_stream(lines))
+        .expect("response is valid")
+}
+
+fn json_response<B: Into<Vec<u8>>>(status: StatusCode, body: B) -> Response {
+    http::Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.into()))
+        .expect("response is valid")
+}
+
+// Check an Accept header for application/x-ndjson, ignoring its parameters.
+fn accepts_ndjson(accept: &str) -> bool {
+    accept
+        .split(',')
+        .filter_map(|media_type| media_type.split(';').next())
+        .any(|media_type| media_type.trim().eq_ignore_ascii_case(NDJSON))
+}
+
+// A weak ETag, from the digest of the environments: the timestamps and durations of the body
+// change at each probe, and are left out of the digest, so bodies with the same ETag are only
+// equivalent, not byte for byte equal.
+fn etag(digest: &str) -> String {
+    format!("W/\"{}\"", digest)
+}
+
+// Check an If-None-Match header, which holds either '*' or a list of ETags.
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/"))
+}
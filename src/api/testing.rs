@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use super::gql::Context;
+use crate::settings::{CacheMode, Settings};
+
+/// The settings of the command line, with its default values.
+pub fn settings() -> Settings {
+    Settings {
+        normalize_coverage: false,
+        disable_playground: false,
+        disable_introspection: false,
+        require_all_healthy: false,
+        warmup: false,
+        config: String::from("env.json"),
+        only: None,
+        watch_config: false,
+        socks_proxy: None,
+        date_format: String::from("%Y%m%d"),
+        time_format: String::from("%H%M%S"),
+        base_path: String::new(),
+        pool_max_idle_per_host: None,
+        pool_idle_timeout: Some(90),
+        request_timeout: 30,
+        tcp_keepalive: None,
+        tcp_nodelay: None,
+        probe_interval: None,
+        probe_jitter: None,
+        probe_jitter_seed: None,
+        cache_mode: CacheMode::Fresh,
+        cache_ttl: 60,
+        max_stale: 0,
+        request_budget: None,
+        probe_retries: 0,
+        retry_budget: None,
+        slow_probe_threshold: None,
+        count_drop_warning: 10,
+        bragi_api_version: None,
+        user_agent: String::from("besp-test"),
+        http2_prior_knowledge: false,
+        max_redirects: None,
+        max_response_bytes: None,
+        max_request_bytes: 1_048_576,
+        client_cert: None,
+        client_key: None,
+        admin_token: None,
+        ca_cert: None,
+        danger_accept_invalid_certs: false,
+        deny: Vec::new(),
+        resolve: Vec::new(),
+        reverse_coordinates: None,
+        segment_counts: false,
+        search_query: None,
+        probe_query_coverage: None,
+        tag_weights: Vec::new(),
+        bragi_metrics: Vec::new(),
+        bragi_metrics_path: String::from("metrics"),
+    }
+}
+
+/// A context without any environment, which logs nowhere.
+pub fn context(settings: Settings) -> Context {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    Context::new(logger, HashMap::new(), settings).unwrap()
+}
@@ -11,15 +11,72 @@ pub enum Error {
     #[snafu(visibility(pub))]
     NotAccessible { url: String, source: reqwest::Error },
 
+    #[snafu(display("Could not open a TCP connection to {}", url))]
+    #[snafu(visibility(pub))]
+    TcpNotReachable { url: String },
+
+    #[snafu(display("URL {} not accessible through proxy {}", url, proxy))]
+    #[snafu(visibility(pub))]
+    ProxyNotAccessible {
+        url: String,
+        proxy: String,
+        source: reqwest::Error,
+    },
+
+    #[snafu(display("URL {} targets {}, which is denied by --deny-cidr", url, target))]
+    #[snafu(visibility(pub))]
+    Denied { url: String, target: String },
+
+    #[snafu(display("URL {} redirected ({}) to {}", url, status, location))]
+    #[snafu(visibility(pub))]
+    Redirected {
+        url: String,
+        status: u16,
+        location: String,
+    },
+
+    #[snafu(display("Could not build HTTP client: {}", msg))]
+    #[snafu(visibility(pub))]
+    ClientError { msg: String, source: reqwest::Error },
+
+    #[snafu(display("Could not read response from {}", url))]
+    #[snafu(visibility(pub))]
+    BodyNotReadable { url: String, source: reqwest::Error },
+
+    #[snafu(display("Response from {} is larger than {} bytes", url, limit))]
+    #[snafu(visibility(pub))]
+    ResponseTooLarge { url: String, limit: u64 },
+
     #[snafu(display("Status {} not accessible", url))]
     #[snafu(visibility(pub))]
     StatusNotAccessible { url: String, source: reqwest::Error },
 
-    // FIXME Not sure how to specify the source type here,
-    // it's a serde deserialization error, but it requires a lifetime...
-    #[snafu(display("JSON Status not readable {}", url))]
+    #[snafu(display(
+        "JSON Status not readable {}: got {} instead of JSON: {}",
+        url,
+        content_type,
+        snippet
+    ))]
     #[snafu(visibility(pub))]
-    StatusNotReadable { url: String, source: reqwest::Error },
+    StatusNotReadable {
+        url: String,
+        content_type: String,
+        snippet: String,
+    },
+
+    #[snafu(display("JSON Status not parsable {}: {}", url, source))]
+    #[snafu(visibility(pub))]
+    StatusNotParsable {
+        url: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("URL {} not parsable: {}", url, source))]
+    #[snafu(visibility(pub))]
+    URLNotParsable {
+        url: String,
+        source: url::ParseError,
+    },
 
     #[snafu(display("elasticsearch url not parsable {}", url))]
     #[snafu(visibility(pub))]
@@ -32,6 +89,14 @@ pub enum Error {
     #[snafu(visibility(pub))]
     DeserializeError { source: serde_json::error::Error },
 
+    #[snafu(display("Unauthorized: {}", msg))]
+    #[snafu(visibility(pub))]
+    Unauthorized { msg: String },
+
+    #[snafu(display("Unexpected panic: {}", msg))]
+    #[snafu(visibility(pub))]
+    Panic { msg: String },
+
     #[snafu(display("lack of imagination: {}", msg))]
     #[snafu(visibility(pub))]
     MiscError { msg: String },
@@ -40,6 +105,10 @@ pub enum Error {
     #[snafu(visibility(pub))]
     IOError { msg: String, source: std::io::Error },
 
+    #[snafu(display("SSH Error: {} - {}", msg, source))]
+    #[snafu(visibility(pub))]
+    SSHError { msg: String, source: ssh2::Error },
+
     #[snafu(display("JSON Error: {} - {}", msg, source))]
     #[snafu(visibility(pub))]
     JSONError {
@@ -48,6 +117,35 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// A short name for the kind of error, used eg as a metric label.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Environment { .. } => "Environment",
+            Error::NotAccessible { .. } => "NotAccessible",
+            Error::TcpNotReachable { .. } => "TcpNotReachable",
+            Error::ProxyNotAccessible { .. } => "ProxyNotAccessible",
+            Error::Denied { .. } => "Denied",
+            Error::Redirected { .. } => "Redirected",
+            Error::ClientError { .. } => "ClientError",
+            Error::BodyNotReadable { .. } => "BodyNotReadable",
+            Error::ResponseTooLarge { .. } => "ResponseTooLarge",
+            Error::StatusNotAccessible { .. } => "StatusNotAccessible",
+            Error::StatusNotReadable { .. } => "StatusNotReadable",
+            Error::StatusNotParsable { .. } => "StatusNotParsable",
+            Error::URLNotParsable { .. } => "URLNotParsable",
+            Error::ElasticsearchURLNotReadable { .. } => "ElasticsearchURLNotReadable",
+            Error::DeserializeError { .. } => "DeserializeError",
+            Error::Unauthorized { .. } => "Unauthorized",
+            Error::Panic { .. } => "Panic",
+            Error::MiscError { .. } => "MiscError",
+            Error::IOError { .. } => "IOError",
+            Error::SSHError { .. } => "SSHError",
+            Error::JSONError { .. } => "JSONError",
+        }
+    }
+}
+
 impl IntoFieldError for Error {
     fn into_field_error(self) -> FieldError {
         match self {
@@ -64,6 +162,56 @@ impl IntoFieldError for Error {
                 FieldError::new("Access Error", graphql_value!({ "internal_error": errmsg }))
             }
 
+            err @ Error::TcpNotReachable { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new(
+                    "TCP Not Reachable Error",
+                    graphql_value!({ "internal_error": errmsg }),
+                )
+            }
+
+            err @ Error::ProxyNotAccessible { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new(
+                    "Proxy Not Accessible Error",
+                    graphql_value!({ "internal_error": errmsg }),
+                )
+            }
+
+            err @ Error::Denied { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new("Denied Error", graphql_value!({ "internal_error": errmsg }))
+            }
+
+            err @ Error::Redirected { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new(
+                    "Redirected Error",
+                    graphql_value!({ "internal_error": errmsg }),
+                )
+            }
+
+            err @ Error::ClientError { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new("Client Error", graphql_value!({ "internal_error": errmsg }))
+            }
+
+            err @ Error::BodyNotReadable { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new(
+                    "Body Not Readable Error",
+                    graphql_value!({ "internal_error": errmsg }),
+                )
+            }
+
+            err @ Error::ResponseTooLarge { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new(
+                    "Response Too Large Error",
+                    graphql_value!({ "internal_error": errmsg }),
+                )
+            }
+
             err @ Error::StatusNotAccessible { .. } => {
                 let errmsg = format!("{}", err);
                 FieldError::new(
@@ -80,6 +228,22 @@ impl IntoFieldError for Error {
                 )
             }
 
+            err @ Error::StatusNotParsable { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new(
+                    "Status Not Parsable Error",
+                    graphql_value!({ "internal_error": errmsg }),
+                )
+            }
+
+            err @ Error::URLNotParsable { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new(
+                    "URL Not Parsable Error",
+                    graphql_value!({ "internal_error": errmsg }),
+                )
+            }
+
             err @ Error::ElasticsearchURLNotReadable { .. } => {
                 let errmsg = format!("{}", err);
                 FieldError::new(
@@ -96,6 +260,22 @@ impl IntoFieldError for Error {
                 )
             }
 
+            err @ Error::Unauthorized { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new(
+                    "Unauthorized Error",
+                    graphql_value!({ "code": "UNAUTHORIZED", "internal_error": errmsg }),
+                )
+            }
+
+            err @ Error::Panic { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new(
+                    "Internal Error",
+                    graphql_value!({ "code": "INTERNAL_PANIC", "internal_error": errmsg }),
+                )
+            }
+
             err @ Error::MiscError { .. } => {
                 let errmsg = format!("{}", err);
                 FieldError::new(
@@ -109,6 +289,11 @@ impl IntoFieldError for Error {
                 FieldError::new("IO Error", graphql_value!({ "internal_error": errmsg }))
             }
 
+            err @ Error::SSHError { .. } => {
+                let errmsg = format!("{}", err);
+                FieldError::new("SSH Error", graphql_value!({ "internal_error": errmsg }))
+            }
+
             err @ Error::JSONError { .. } => {
                 let errmsg = format!("{}", err);
                 FieldError::new("JSON Error", graphql_value!({ "internal_error": errmsg }))
@@ -1,2 +1,6 @@
 pub mod api;
 pub mod error;
+pub mod logfmt;
+pub mod metrics;
+pub mod settings;
+pub mod tunnel;
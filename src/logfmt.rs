@@ -0,0 +1,67 @@
+use chrono::{SecondsFormat, Utc};
+use slog::{Drain, Key, OwnedKVList, Record, Serializer, KV};
+use std::fmt;
+use std::io::{self, Write};
+
+/// A drain writing each record on stderr as a line of logfmt, eg
+/// `ts=2020-06-01T12:00:00.000Z level=info msg="Probing dev" env=dev duration_ms=123`.
+/// The fields of the record come before those of the logger.
+pub struct Logfmt;
+
+impl Drain for Logfmt {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let mut line = format!(
+            "ts={} level={} msg={}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            record.level().as_str().to_lowercase(),
+            quote(&record.msg().to_string())
+        );
+        let mut serializer = LineSerializer(&mut line);
+        record.kv().serialize(record, &mut serializer)?;
+        values.serialize(record, &mut serializer)?;
+        line.push('\n');
+        io::stderr().write_all(line.as_bytes())
+    }
+}
+
+// Appends the fields to a line, as ' key=value'
+struct LineSerializer<'a>(&'a mut String);
+
+impl<'a> Serializer for LineSerializer<'a> {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.push(' ');
+        self.0.push_str(key);
+        self.0.push('=');
+        self.0.push_str(&quote(&val.to_string()));
+        Ok(())
+    }
+}
+
+// Values are quoted when they are empty, or contain spaces, quotes or equal signs, with quotes,
+// backslashes and line breaks escaped.
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '=' || c == '\\');
+    if plain {
+        return String::from(value);
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
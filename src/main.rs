@@ -1,19 +1,47 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::future::FutureExt;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
 use serde::Deserialize;
-use slog::{info, o, Drain, Logger};
+use slog::{error, info, o, warn, Drain, Logger};
 use snafu::ResultExt;
+use std::backtrace::Backtrace;
 use std::collections::HashMap;
-use std::net::ToSocketAddrs;
-use warp::{self, http, Filter};
+use std::convert::Infallible;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+use warp::filters::BoxedFilter;
+use warp::{self, http, Filter, Reply};
 
-use besp::api::gql;
+use besp::api::{background, environment, events, gql, grafana, introspection, rest};
 use besp::error;
+use besp::logfmt::Logfmt;
+use besp::metrics;
+use besp::settings::{CacheMode, DenyRule, Env, EnvEntry, Settings};
 
-#[derive(Debug, Deserialize)]
-pub struct Env {
-    pub env: String,
-    pub url: String,
-}
+const DEFAULT_USER_AGENT: &str = concat!("besp/", env!("CARGO_PKG_VERSION"));
+
+const REQUEST_ID: &str = "x-request-id";
+
+// Where the environments are read from, unless --config says otherwise
+const CONFIG_FILE: &str = "env.json";
+
+// How long env.json must be left alone after a change before it is reloaded, so that we don't
+// read it while it is being written.
+const CONFIG_DEBOUNCE: Duration = Duration::from_secs(2);
+
+// Cache-Control of the responses which only change with the version of the server. They are
+// short lived, so that browsers pick up a new version soon after a deployment.
+const STATIC_CACHE_CONTROL: &str = "public, max-age=300";
 
 #[tokio::main]
 async fn main() -> Result<(), error::Error> {
@@ -36,13 +64,535 @@ async fn main() -> Result<(), error::Error> {
                 .default_value("8080")
                 .help("Port"),
         )
+        .arg(
+            Arg::with_name("unix_socket")
+                .value_name("PATH")
+                .long("unix-socket")
+                .conflicts_with_all(&["address", "port"])
+                .help("Listen on this Unix domain socket instead of a TCP port"),
+        )
+        .arg(
+            Arg::with_name("normalize_coverage")
+                .long("normalize-coverage")
+                .help("Lowercase and trim coverages extracted from index names"),
+        )
+        .arg(
+            Arg::with_name("disable_playground")
+                .long("disable-playground")
+                .help("Do not serve the GraphQL playground"),
+        )
+        .arg(
+            Arg::with_name("disable_introspection")
+                .long("disable-introspection")
+                .help("Reject GraphQL queries on the schema (__schema and __type)"),
+        )
+        .arg(
+            Arg::with_name("require_all_healthy")
+                .long("require-all-healthy")
+                .help("Probe all environments at startup, and exit if any is not available"),
+        )
+        .arg(
+            Arg::with_name("warmup")
+                .long("warmup")
+                .help("Probe all environments once before accepting connections"),
+        )
+        .arg(
+            Arg::with_name("socks_proxy")
+                .value_name("URL")
+                .long("socks-proxy")
+                .help("SOCKS5 proxy used to reach environments (eg socks5://localhost:1080)"),
+        )
+        .arg(
+            Arg::with_name("date_format")
+                .value_name("FORMAT")
+                .long("date-format")
+                .default_value("%Y%m%d")
+                .help("Format of the date segment of index names"),
+        )
+        .arg(
+            Arg::with_name("time_format")
+                .value_name("FORMAT")
+                .long("time-format")
+                .default_value("%H%M%S")
+                .help("Format of the time segment of index names"),
+        )
+        .arg(
+            Arg::with_name("only")
+                .value_name("ENV")
+                .long("only")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only probe the given environment (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .value_name("PATH|URL")
+                .long("config")
+                .default_value(CONFIG_FILE)
+                .help("File or http(s) url the environments are read from"),
+        )
+        .arg(
+            Arg::with_name("watch_config")
+                .long("watch-config")
+                .help("Reload the environments when env.json changes"),
+        )
+        .arg(
+            Arg::with_name("base_path")
+                .value_name("PATH")
+                .long("base-path")
+                .default_value("")
+                .help("Path prefix for all routes (eg /besp)"),
+        )
+        .arg(
+            Arg::with_name("pool_max_idle_per_host")
+                .value_name("COUNT")
+                .long("pool-max-idle-per-host")
+                .help("Maximum number of idle connections kept per host (unbounded by default)"),
+        )
+        .arg(
+            Arg::with_name("pool_idle_timeout")
+                .value_name("SECONDS")
+                .long("pool-idle-timeout")
+                .default_value("90")
+                .help("Time after which idle connections are closed"),
+        )
+        .arg(
+            Arg::with_name("request_timeout")
+                .value_name("SECONDS")
+                .long("request-timeout")
+                .default_value("30")
+                .help("Time after which a request to an environment fails"),
+        )
+        .arg(
+            Arg::with_name("tcp_keepalive")
+                .value_name("SECONDS")
+                .long("tcp-keepalive")
+                .help("Interval of TCP keepalive probes on connections (disabled by default)"),
+        )
+        .arg(
+            Arg::with_name("tcp_nodelay")
+                .value_name("BOOL")
+                .long("tcp-nodelay")
+                .possible_values(&["true", "false"])
+                .help("Send small requests at once, without Nagle's algorithm (reqwest's default)"),
+        )
+        .arg(
+            Arg::with_name("config_retry")
+                .value_name("COUNT")
+                .long("config-retry")
+                .default_value("0")
+                .help("Number of times to retry loading env.json before giving up"),
+        )
+        .arg(
+            Arg::with_name("config_retry_delay")
+                .value_name("SECONDS")
+                .long("config-retry-delay")
+                .default_value("1")
+                .help("Delay between attempts to load env.json"),
+        )
+        .arg(
+            Arg::with_name("allow_empty_config")
+                .long("allow-empty-config")
+                .help("Start without any environment when env.json does not exist"),
+        )
+        .arg(
+            Arg::with_name("probe_interval")
+                .value_name("SECONDS")
+                .long("probe-interval")
+                .help("Probe all environments in the background at this interval"),
+        )
+        .arg(
+            Arg::with_name("cache_mode")
+                .value_name("MODE")
+                .long("cache-mode")
+                .possible_values(&["fresh", "swr"])
+                .default_value("fresh")
+                .help("Probe at each query, or serve cached results refreshed in the background"),
+        )
+        .arg(
+            Arg::with_name("cache_ttl")
+                .value_name("SECONDS")
+                .long("cache-ttl")
+                .default_value("60")
+                .help("Age after which cached results are refreshed (with --cache-mode swr)"),
+        )
+        .arg(
+            Arg::with_name("max_stale")
+                .value_name("SECONDS")
+                .long("max-stale")
+                .default_value("0")
+                .help("How long after --cache-ttl a cached result is served when probes fail"),
+        )
+        .arg(
+            Arg::with_name("probe_jitter")
+                .value_name("SECONDS")
+                .long("probe-jitter")
+                .help("Delay the first background probe of each environment by a random time"),
+        )
+        .arg(
+            Arg::with_name("probe_jitter_seed")
+                .value_name("SEED")
+                .long("probe-jitter-seed")
+                .help("Seed of the random delays of --probe-jitter, for reproducible runs"),
+        )
+        .arg(
+            Arg::with_name("request_budget")
+                .value_name("COUNT")
+                .long("request-budget")
+                .help("Defer background probes to the next cycle after this many requests"),
+        )
+        .arg(
+            Arg::with_name("probe_retries")
+                .value_name("COUNT")
+                .long("probe-retries")
+                .default_value("0")
+                .help("Retry the first request to bragi this many times when it can't connect"),
+        )
+        .arg(
+            Arg::with_name("retry_budget")
+                .value_name("COUNT")
+                .long("retry-budget")
+                .help("Maximum number of retries across all probes in each probe cycle"),
+        )
+        .arg(
+            Arg::with_name("slow_probe_threshold")
+                .value_name("MS")
+                .long("slow-probe-threshold")
+                .help("Log a warning when probing an environment takes longer (in milliseconds)"),
+        )
+        .arg(
+            Arg::with_name("count_drop_warning")
+                .value_name("PERCENT")
+                .long("count-drop-warning")
+                .default_value("10")
+                .help("Warn when an index loses more than this share of its documents"),
+        )
+        .arg(
+            Arg::with_name("bragi_api_version")
+                .value_name("VERSION")
+                .long("bragi-api-version")
+                .possible_values(&["v1", "v2"])
+                .help("Shape of bragi's status response (all shapes are tried by default)"),
+        )
+        .arg(
+            Arg::with_name("user_agent")
+                .value_name("USER_AGENT")
+                .long("user-agent")
+                .default_value(DEFAULT_USER_AGENT)
+                .help("User agent of probe requests"),
+        )
+        .arg(
+            Arg::with_name("http2_prior_knowledge")
+                .long("http2-prior-knowledge")
+                .help("Send probe requests over HTTP/2 without negotiating it"),
+        )
+        .arg(
+            Arg::with_name("max_redirects")
+                .value_name("COUNT")
+                .long("max-redirects")
+                .help("Maximum number of redirects followed (10 by default, 0 to report them)"),
+        )
+        .arg(
+            Arg::with_name("max_response_bytes")
+                .value_name("BYTES")
+                .long("max-response-bytes")
+                .help("Reject bragi or elasticsearch responses larger than this"),
+        )
+        .arg(
+            Arg::with_name("max_request_bytes")
+                .value_name("BYTES")
+                .long("max-request-bytes")
+                .default_value("1048576")
+                .help("Reject GraphQL requests with a body larger than this (413)"),
+        )
+        .arg(
+            Arg::with_name("client_cert")
+                .value_name("FILE")
+                .long("client-cert")
+                .requires("client_key")
+                .help("PEM client certificate, for elasticsearch clusters requiring mutual TLS"),
+        )
+        .arg(
+            Arg::with_name("client_key")
+                .value_name("FILE")
+                .long("client-key")
+                .requires("client_cert")
+                .help("PEM private key of the client certificate"),
+        )
+        .arg(
+            Arg::with_name("admin_token")
+                .value_name("TOKEN")
+                .long("admin-token")
+                .env("BESP_ADMIN_TOKEN")
+                .hide_env_values(true)
+                .help("Bearer token allowing the probeUrl mutation (disabled without it)"),
+        )
+        .arg(
+            Arg::with_name("ca_cert")
+                .value_name("FILE")
+                .long("ca-cert")
+                .help("PEM CA certificate to trust, in addition to the system ones"),
+        )
+        .arg(
+            Arg::with_name("danger_accept_invalid_certs")
+                .long("danger-accept-invalid-certs")
+                .help("Do not verify TLS certificates (never use in production)"),
+        )
+        .arg(
+            Arg::with_name("resolve")
+                .value_name("HOST:IP")
+                .long("resolve")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Send plain HTTP probe requests for HOST to IP, bypassing DNS (can be \
+                     repeated); HTTPS requests are not affected",
+                ),
+        )
+        .arg(
+            Arg::with_name("deny_cidr")
+                .value_name("CIDR|HOST")
+                .long("deny-cidr")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Refuse to probe this range of addresses or host (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("tag_weight")
+                .value_name("TAG=WEIGHT")
+                .long("tag-weight")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Weight of the environments with TAG in the fleet health (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("segment_counts")
+                .long("segment-counts")
+                .help("Count the segments of each index (heavy on elasticsearch)"),
+        )
+        .arg(
+            Arg::with_name("reverse_probe")
+                .long("reverse-probe")
+                .help("Check that bragi answers reverse geocoding requests"),
+        )
+        .arg(
+            Arg::with_name("reverse_coordinates")
+                .value_name("LAT,LON")
+                .long("reverse-coordinates")
+                .default_value("48.8566,2.3522")
+                .help("Coordinates of the reverse geocoding probe"),
+        )
+        .arg(
+            Arg::with_name("search_query")
+                .value_name("TEXT")
+                .long("search-query")
+                .help("Check that bragi finds results when autocompleting this text"),
+        )
+        .arg(
+            Arg::with_name("probe_query_coverage")
+                .value_name("COVERAGE")
+                .long("probe-query-coverage")
+                .requires("search_query")
+                .help("Coverage searched by --search-query, unless the environment sets one"),
+        )
+        .arg(
+            Arg::with_name("bragi_metric")
+                .value_name("NAME")
+                .long("bragi-metric")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Report this metric of bragi with each environment (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("bragi_metrics_path")
+                .value_name("PATH")
+                .long("bragi-metrics-path")
+                .default_value("metrics")
+                .help("Path of bragi's metrics endpoint (eg prometheus)"),
+        )
+        .arg(
+            Arg::with_name("log_format")
+                .value_name("FORMAT")
+                .long("log-format")
+                .possible_values(&["text", "logfmt"])
+                .default_value("text")
+                .help("Format of the log records"),
+        )
+        .arg(
+            Arg::with_name("log_chan_size")
+                .value_name("COUNT")
+                .long("log-chan-size")
+                .default_value("128")
+                .help("Number of log records buffered before they are written"),
+        )
+        .arg(
+            Arg::with_name("log_overflow")
+                .value_name("STRATEGY")
+                .long("log-overflow")
+                .possible_values(&["block", "drop"])
+                .default_value("drop")
+                .help("What to do with log records when the buffer is full"),
+        )
+        .arg(
+            Arg::with_name("validate_schema")
+                .long("validate-schema")
+                .help("Check that the GraphQL schema can be introspected, and exit"),
+        )
+        .arg(
+            Arg::with_name("dump_schema")
+                .value_name("PATH")
+                .long("dump-schema")
+                .help("Write the GraphQL schema (SDL) to this file at startup"),
+        )
+        .subcommand(
+            SubCommand::with_name("schema")
+                .about("Print the GraphQL schema (SDL) on stdout and exit"),
+        )
         .get_matches();
 
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    let logger = slog::Logger::root(drain, o!());
+    if matches.subcommand_matches("schema").is_some() {
+        println!("{}", gql::schema().as_schema_language());
+        return Ok(());
+    }
+
+    // Blocking never loses a log record, but a slow terminal then slows down the requests.
+    let overflow_strategy = match matches.value_of("log_overflow") {
+        Some("block") => slog_async::OverflowStrategy::Block,
+        _ => slog_async::OverflowStrategy::DropAndReport,
+    };
+    let chan_size = parse_arg(&matches, "log_chan_size")?.unwrap_or(128);
+    let drain = match matches.value_of("log_format") {
+        Some("logfmt") => slog_async::Async::new(Logfmt.fuse())
+            .chan_size(chan_size)
+            .overflow_strategy(overflow_strategy)
+            .build(),
+        _ => {
+            let decorator = slog_term::TermDecorator::new().build();
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            slog_async::Async::new(drain)
+                .chan_size(chan_size)
+                .overflow_strategy(overflow_strategy)
+                .build()
+        }
+    };
+    let logger = slog::Logger::root(drain.fuse(), o!());
+
+    // Panics in probes are reported to clients as errors, but we still want to know where
+    // they come from.
+    let panic_logger = logger.clone();
+    std::panic::set_hook(Box::new(move |info| {
+        error!(panic_logger, "{}\n{}", info, Backtrace::force_capture());
+    }));
+
+    if let Some(path) = matches.value_of("dump_schema") {
+        dump_schema(path).await?;
+        info!(logger, "GraphQL schema written to {}", path);
+    }
+
+    let listener = match matches.value_of("unix_socket") {
+        Some(path) => Listener::Unix(PathBuf::from(path)),
+        None => {
+            let (host, port) = tcp_addr(&matches)?;
+            Listener::Tcp(host, port)
+        }
+    };
 
+    let settings = Settings {
+        normalize_coverage: matches.is_present("normalize_coverage"),
+        disable_playground: matches.is_present("disable_playground"),
+        disable_introspection: matches.is_present("disable_introspection"),
+        require_all_healthy: matches.is_present("require_all_healthy"),
+        warmup: matches.is_present("warmup"),
+        only: matches
+            .values_of("only")
+            .map(|names| names.map(String::from).collect()),
+        watch_config: matches.is_present("watch_config"),
+        socks_proxy: matches.value_of("socks_proxy").map(String::from),
+        date_format: String::from(matches.value_of("date_format").unwrap_or("%Y%m%d")),
+        time_format: String::from(matches.value_of("time_format").unwrap_or("%H%M%S")),
+        base_path: String::from(matches.value_of("base_path").unwrap_or("")),
+        pool_max_idle_per_host: parse_arg(&matches, "pool_max_idle_per_host")?,
+        pool_idle_timeout: parse_arg(&matches, "pool_idle_timeout")?,
+        request_timeout: parse_arg(&matches, "request_timeout")?.unwrap_or(30),
+        tcp_keepalive: parse_arg(&matches, "tcp_keepalive")?,
+        tcp_nodelay: parse_arg(&matches, "tcp_nodelay")?,
+        probe_interval: parse_arg(&matches, "probe_interval")?,
+        probe_jitter: parse_arg(&matches, "probe_jitter")?,
+        probe_jitter_seed: parse_arg(&matches, "probe_jitter_seed")?,
+        request_budget: parse_arg(&matches, "request_budget")?,
+        probe_retries: parse_arg(&matches, "probe_retries")?.unwrap_or(0),
+        retry_budget: parse_arg(&matches, "retry_budget")?,
+        slow_probe_threshold: parse_arg(&matches, "slow_probe_threshold")?,
+        cache_mode: parse_arg(&matches, "cache_mode")?.unwrap_or(CacheMode::Fresh),
+        cache_ttl: parse_arg(&matches, "cache_ttl")?.unwrap_or(60),
+        max_stale: parse_arg(&matches, "max_stale")?.unwrap_or(0),
+        count_drop_warning: parse_arg(&matches, "count_drop_warning")?.unwrap_or(10),
+        bragi_api_version: parse_arg(&matches, "bragi_api_version")?,
+        user_agent: String::from(matches.value_of("user_agent").unwrap_or(DEFAULT_USER_AGENT)),
+        http2_prior_knowledge: matches.is_present("http2_prior_knowledge"),
+        max_redirects: parse_arg(&matches, "max_redirects")?,
+        max_response_bytes: parse_arg(&matches, "max_response_bytes")?,
+        max_request_bytes: parse_arg(&matches, "max_request_bytes")?.unwrap_or(1_048_576),
+        client_cert: matches.value_of("client_cert").map(String::from),
+        client_key: matches.value_of("client_key").map(String::from),
+        config: String::from(matches.value_of("config").unwrap_or(CONFIG_FILE)),
+        admin_token: matches.value_of("admin_token").map(String::from),
+        ca_cert: matches.value_of("ca_cert").map(String::from),
+        danger_accept_invalid_certs: matches.is_present("danger_accept_invalid_certs"),
+        deny: matches
+            .values_of("deny_cidr")
+            .map(|values| values.map(parse_deny).collect::<Result<_, _>>())
+            .unwrap_or_else(|| Ok(Vec::new()))?,
+        resolve: matches
+            .values_of("resolve")
+            .map(|values| values.map(parse_resolve).collect::<Result<_, _>>())
+            .unwrap_or_else(|| Ok(Vec::new()))?,
+        reverse_coordinates: match matches.value_of("reverse_coordinates") {
+            Some(value) if matches.is_present("reverse_probe") => Some(parse_coordinates(value)?),
+            _ => None,
+        },
+        tag_weights: matches
+            .values_of("tag_weight")
+            .map(|values| values.map(parse_tag_weight).collect::<Result<_, _>>())
+            .unwrap_or_else(|| Ok(Vec::new()))?,
+        segment_counts: matches.is_present("segment_counts"),
+        search_query: matches.value_of("search_query").map(String::from),
+        probe_query_coverage: matches.value_of("probe_query_coverage").map(String::from),
+        bragi_metrics: matches
+            .values_of("bragi_metric")
+            .map(|names| names.map(String::from).collect())
+            .unwrap_or_default(),
+        bragi_metrics_path: String::from(
+            matches.value_of("bragi_metrics_path").unwrap_or("metrics"),
+        ),
+    };
+
+    if matches.is_present("validate_schema") {
+        let context = gql::Context::new(logger.clone(), HashMap::new(), settings)?;
+        validate_schema(&context).await?;
+        info!(logger, "GraphQL schema is valid");
+        return Ok(());
+    }
+
+    let config_retry = parse_arg(&matches, "config_retry")?.unwrap_or(0);
+    let config_retry_delay = parse_arg(&matches, "config_retry_delay")?.unwrap_or(1);
+    let envs = load_envs(
+        &settings,
+        config_retry,
+        Duration::from_secs(config_retry_delay),
+        matches.is_present("allow_empty_config"),
+        &logger,
+    )
+    .await?;
+    let envs = configure_envs(envs, &settings)?;
+
+    run_server(listener, logger, envs, settings).await?;
+
+    Ok(())
+}
+
+// The host and port given on the command line.
+fn tcp_addr(matches: &ArgMatches) -> Result<(String, u16), error::Error> {
     let addr = matches
         .value_of("address")
         .ok_or_else(|| error::Error::MiscError {
@@ -59,87 +609,861 @@ async fn main() -> Result<(), error::Error> {
         msg: format!("Could not parse into a valid port number ({})", err),
     })?;
 
-    // XXXX TODO Move this to tokio fs
-    let envs = tokio::fs::read_to_string("env.json")
-        .await
-        .context(error::IOError {
-            msg: String::from("Could not open env.json"),
-        })?;
-    let envs: Vec<Env> = serde_json::from_str(&envs).context(error::JSONError {
-        msg: String::from("Could not deserialize env.json content"),
-    })?;
-    let envs: HashMap<String, String> = envs.into_iter().map(|e| (e.env, e.url)).collect();
+    Ok((String::from(addr), port))
+}
+
+// Write the schema to the file, as printed by the schema subcommand, so that a committed copy
+// can be kept in sync with the binary.
+async fn dump_schema(path: &str) -> Result<(), error::Error> {
+    let sdl = format!("{}\n", gql::schema().as_schema_language());
+    tokio::fs::write(path, sdl).await.context(error::IOError {
+        msg: format!("Could not write the GraphQL schema to {}", path),
+    })
+}
+
+// Run an introspection query through the schema, to check that it can be built and executed.
+async fn validate_schema(context: &gql::Context) -> Result<(), error::Error> {
+    let schema = gql::schema();
+    let query = "{ __schema { queryType { name } types { name fields { name } } } }";
+    match juniper::execute(query, None, &schema, &juniper::Variables::new(), context).await {
+        Ok((_, errors)) if errors.is_empty() => Ok(()),
+        Ok((_, errors)) => Err(error::Error::MiscError {
+            msg: format!("Invalid GraphQL schema: {:?}", errors),
+        }),
+        Err(err) => Err(error::Error::MiscError {
+            msg: format!("Invalid GraphQL schema: {:?}", err),
+        }),
+    }
+}
 
-    run_server((addr, port), logger, envs).await?;
+// Read the environments from env.json, or wherever --config says. The file may not be there
+// yet when the container starts, or the service serving it may not be up, so we try again a few
+// times before giving up. If the file is still not there and `allow_empty` is set, we start
+// without any environment. A configuration we can't read or parse is always an error.
+async fn load_envs(
+    settings: &Settings,
+    retries: u32,
+    delay: Duration,
+    allow_empty: bool,
+    logger: &Logger,
+) -> Result<Vec<Env>, error::Error> {
+    // The server is not up yet, but this client is configured like the one it will use.
+    let client = settings.client(false)?;
+    let mut attempt = 0;
+    loop {
+        match read_envs(&settings.config, &client).await {
+            Ok(envs) => return Ok(envs),
+            Err(err) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    logger,
+                    "Could not load configuration (attempt {}/{}): {}", attempt, retries, err
+                );
+                tokio::time::delay_for(delay).await;
+            }
+            Err(error::Error::IOError { ref source, .. })
+                if allow_empty && source.kind() == std::io::ErrorKind::NotFound =>
+            {
+                info!(
+                    logger,
+                    "No {}, starting without any environment", settings.config
+                );
+                return Ok(Vec::new());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Read the environments from a file, or fetch them from an http(s) url.
+async fn read_envs(config: &str, client: &reqwest::Client) -> Result<Vec<Env>, error::Error> {
+    let name = config_name(config);
+    let envs = if is_url(config) {
+        client
+            .get(config)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .context(error::NotAccessible { url: name.clone() })?
+            .text()
+            .await
+            .context(error::BodyNotReadable { url: name.clone() })?
+    } else {
+        tokio::fs::read_to_string(config)
+            .await
+            .context(error::IOError {
+                msg: format!("Could not open {}", name),
+            })?
+    };
+    serde_json::from_str(&envs)
+        .context(error::JSONError {
+            msg: format!("Could not deserialize {} content", name),
+        })
+        .map(expand_envs)
+}
+
+fn is_url(config: &str) -> bool {
+    config.starts_with("http://") || config.starts_with("https://")
+}
+
+// The configuration location, as logged: urls may hold credentials.
+fn config_name(config: &str) -> String {
+    if is_url(config) {
+        environment::redact_url(config)
+    } else {
+        String::from(config)
+    }
+}
+
+// Check the environments read from env.json, and only keep those given with --only.
+fn configure_envs(
+    envs: Vec<Env>,
+    settings: &Settings,
+) -> Result<HashMap<String, Env>, error::Error> {
+    check_index_exclude_patterns(&envs)?;
+    let mut envs: HashMap<String, Env> = envs.into_iter().map(|e| (e.env.clone(), e)).collect();
+
+    if let Some(only) = &settings.only {
+        if let Some(unknown) = only.iter().find(|name| !envs.contains_key(*name)) {
+            return Err(error::Error::Environment {
+                env: unknown.clone(),
+            });
+        }
+        envs.retain(|name, _| only.contains(name));
+    }
+    Ok(envs)
+}
+
+// Reload the environments when env.json changes (with --watch-config), or on SIGHUP. When the
+// file can't be watched, SIGHUP still works.
+async fn reload_envs(context: gql::Context) {
+    let config = &context.settings.config;
+    let name = config_name(config);
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    if context.settings.watch_config && is_url(config) {
+        warn!(
+            context.logger,
+            "Can't watch {} for changes, send SIGHUP to reload it", name
+        );
+    } else if context.settings.watch_config {
+        match watch_config_file(PathBuf::from(config), sender) {
+            Ok(()) => info!(context.logger, "Watching {} for changes", name),
+            Err(err) => warn!(
+                context.logger,
+                "Could not watch {} ({}), send SIGHUP to reload it", name, err
+            ),
+        }
+    }
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!(context.logger, "Could not handle SIGHUP: {}", err);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            Some(()) = receiver.recv() => {}
+            Some(()) = hangup.recv() => {}
+            else => return,
+        }
+        match read_envs(config, &context.client)
+            .await
+            .and_then(|envs| configure_envs(envs, &context.settings))
+        {
+            Ok(envs) => reconfigure(&context, envs),
+            Err(err) => warn!(
+                context.logger,
+                "Could not reload {}, keeping the current environments: {}", name, err
+            ),
+        }
+    }
+}
+
+// Replace the environments of the running server, and log what changed.
+fn reconfigure(context: &gql::Context, envs: HashMap<String, Env>) {
+    let current = context.config.read().unwrap().clone();
+    let added = joined_names(&envs, |name| !current.contains_key(name));
+    let removed = joined_names(&current, |name| !envs.contains_key(name));
+    let changed = joined_names(&envs, |name| {
+        current.get(name).map_or(false, |env| *env != envs[name])
+    });
+    if added.is_empty() && removed.is_empty() && changed.is_empty() {
+        return;
+    }
+    info!(
+        context.logger,
+        "Reloaded {}: added [{}], removed [{}], changed [{}]",
+        config_name(&context.settings.config),
+        added,
+        removed,
+        changed
+    );
+    if context.insecure_client.is_none() && !context.settings.danger_accept_invalid_certs {
+        if let Some(env) = envs.values().find(|env| env.insecure) {
+            warn!(
+                context.logger,
+                "Certificates of environment {} are verified until the server restarts", env.env
+            );
+        }
+    }
+    context.reconfigure(envs);
+}
+
+// The sorted names of the environments accepted by the filter, separated by commas.
+fn joined_names(envs: &HashMap<String, Env>, filter: impl Fn(&str) -> bool) -> String {
+    let mut names: Vec<&str> = envs
+        .keys()
+        .map(String::as_str)
+        .filter(|name| filter(*name))
+        .collect();
+    names.sort_unstable();
+    names.join(", ")
+}
+
+// Watch the directory of the configuration file in a thread of its own, and send a message each
+// time the file is written, created or renamed, once writes have settled.
+fn watch_config_file(path: PathBuf, sender: UnboundedSender<()>) -> Result<(), String> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path.file_name().map(OsStr::to_os_string);
+    let (ready_sender, ready) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let (events_sender, events) = std::sync::mpsc::channel();
+        // Editors and configuration management often replace the file rather than write it, so
+        // we watch its directory.
+        let watcher = notify::watcher(events_sender, CONFIG_DEBOUNCE).and_then(|mut watcher| {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+        let _watcher = match watcher {
+            Ok(watcher) => {
+                let _ = ready_sender.send(Ok(()));
+                watcher
+            }
+            Err(err) => {
+                let _ = ready_sender.send(Err(err.to_string()));
+                return;
+            }
+        };
+        for event in events {
+            let path = match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Rename(_, path) => path,
+                _ => continue,
+            };
+            if path.file_name() == file_name.as_deref() && sender.send(()).is_err() {
+                return;
+            }
+        }
+    });
+    ready
+        .recv()
+        .unwrap_or_else(|_| Err(String::from("the watcher stopped")))
+}
+
+// Turn the entries of env.json into environments, expanding templates. An environment given
+// explicitly takes precedence over one with the same name from a template.
+fn expand_envs(entries: Vec<EnvEntry>) -> Vec<Env> {
+    let mut envs = Vec::new();
+    let mut templated = Vec::new();
+    for entry in entries {
+        match entry {
+            EnvEntry::Env(env) => envs.push(env),
+            EnvEntry::Template(template) => {
+                templated.extend(template.names.iter().map(|name| Env {
+                    env: name.clone(),
+                    url: template.url_template.replace("{name}", name),
+                    ..Default::default()
+                }))
+            }
+        }
+    }
+    templated.retain(|env: &Env| !envs.iter().any(|explicit| explicit.env == env.env));
+    envs.extend(templated);
+    envs
+}
 
+// Make sure the index exclude patterns of all environments are valid globs.
+fn check_index_exclude_patterns(envs: &[Env]) -> Result<(), error::Error> {
+    for env in envs {
+        for pattern in env.index_exclude_patterns.iter() {
+            glob::Pattern::new(pattern).map_err(|err| error::Error::MiscError {
+                msg: format!(
+                    "Invalid index exclude pattern '{}' in environment {} ({})",
+                    pattern, env.env, err
+                ),
+            })?;
+        }
+    }
     Ok(())
 }
 
+// Parse the value of an optional argument.
+fn parse_arg<T>(matches: &ArgMatches, name: &str) -> Result<Option<T>, error::Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    matches
+        .value_of(name)
+        .map(|value| {
+            value.parse::<T>().map_err(|err| error::Error::MiscError {
+                msg: format!("Could not parse {} argument '{}' ({})", name, value, err),
+            })
+        })
+        .transpose()
+}
+
+// Parse a --reverse-coordinates argument, eg '48.8566,2.3522'.
+fn parse_coordinates(value: &str) -> Result<(f64, f64), error::Error> {
+    let mut parts = value.splitn(2, ',').map(|part| part.trim().parse::<f64>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(lat)), Some(Ok(lon)))
+            if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) =>
+        {
+            Ok((lat, lon))
+        }
+        _ => Err(error::Error::MiscError {
+            msg: format!(
+                "Could not parse reverse coordinates '{}' (expected LAT,LON)",
+                value
+            ),
+        }),
+    }
+}
+
+// Parse a --resolve argument, eg 'bragi.prod:10.0.0.1'.
+fn parse_resolve(value: &str) -> Result<(String, IpAddr), error::Error> {
+    let mut parts = value.splitn(2, ':');
+    match (parts.next(), parts.next().map(IpAddr::from_str)) {
+        (Some(host), Some(Ok(addr))) if !host.is_empty() => Ok((String::from(host), addr)),
+        _ => Err(error::Error::MiscError {
+            msg: format!(
+                "Could not parse resolve argument '{}' (expected HOST:IP)",
+                value
+            ),
+        }),
+    }
+}
+
+// Parse a --deny-cidr argument, eg '169.254.0.0/16', '10.1.2.3' or 'metadata.internal'.
+fn parse_deny(value: &str) -> Result<DenyRule, error::Error> {
+    DenyRule::from_str(value).map_err(|err| error::Error::MiscError {
+        msg: format!("Could not parse deny-cidr argument '{}' ({})", value, err),
+    })
+}
+
+// Parse a --tag-weight argument, eg 'prod=3'.
+fn parse_tag_weight(value: &str) -> Result<(String, f64), error::Error> {
+    let mut parts = value.splitn(2, '=');
+    match (parts.next(), parts.next().map(f64::from_str)) {
+        (Some(tag), Some(Ok(weight))) if !tag.is_empty() && weight >= 0.0 => {
+            Ok((String::from(tag), weight))
+        }
+        _ => Err(error::Error::MiscError {
+            msg: format!(
+                "Could not parse tag weight '{}' (expected TAG=WEIGHT, with a positive weight)",
+                value
+            ),
+        }),
+    }
+}
+
+/// Where the server accepts connections
+enum Listener {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
 async fn run_server(
-    addr: impl ToSocketAddrs,
+    listener: Listener,
     logger: Logger,
-    envs: HashMap<String, String>,
+    envs: HashMap<String, Env>,
+    settings: Settings,
 ) -> Result<(), error::Error> {
-    let logger1 = logger.clone();
-    let envs1 = envs.clone();
-    let state = warp::any().map(move || gql::Context {
-        logger: logger1.clone(),
-        envs: envs1.clone(),
-    });
+    let disable_playground = settings.disable_playground;
+    let disable_introspection = settings.disable_introspection;
+    let max_request_bytes = settings.max_request_bytes;
+    let base_path = normalize_base_path(&settings.base_path);
+    let context = gql::Context::new(logger.clone(), envs, settings)?;
 
+    if context.settings.require_all_healthy {
+        check_all_healthy(&context).await?;
+        info!(logger, "All environments are available");
+    } else if context.settings.warmup {
+        // Checking that all environments are healthy is already a warmup.
+        warmup(&context).await;
+    }
+
+    // Environments probed in the background may be added by a reload, so the loop always runs.
+    tokio::spawn(background::probe_loop(context.clone()));
+    tokio::spawn(reload_envs(context.clone()));
+    tokio::spawn(events::record_status_changes(
+        context.events.subscribe(),
+        logger.clone(),
+    ));
+
+    let state = warp::header::optional::<String>(REQUEST_ID)
+        .and(warp::header::optional::<String>("authorization"))
+        .map(
+            move |request_id: Option<String>, authorization: Option<String>| {
+                context.for_request(request_id, authorization)
+            },
+        );
+
+    let graphql_url = format!("{}/graphql", base_path);
+    let subscriptions_url = format!("{}/subscriptions", base_path);
     let playground = warp::get()
         .and(warp::path("playground"))
-        .and(playground_filter("/graphql", Some("/subscriptions")));
+        .and(playground_filter(&graphql_url, Some(&subscriptions_url)));
 
-    let graphql_filter = juniper_warp::make_graphql_filter(gql::schema(), state.boxed());
+    let graphql_filter = if disable_introspection {
+        introspection::graphql_filter(state.clone().boxed())
+    } else {
+        juniper_warp::make_graphql_filter(gql::schema(), state.clone().boxed())
+    };
 
-    let graphql = warp::path!("graphql").and(graphql_filter);
+    let environments = warp::get()
+        .and(warp::path!("environments"))
+        .and(warp::query::<rest::EnvironmentsQuery>())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("accept"))
+        .and(state.clone())
+        .and_then(rest::environments);
 
-    let routes = playground.or(graphql);
+    // Only POST requests have a body, GET requests carry the query in the query string.
+    let body_limit = warp::post()
+        .and(warp::body::content_length_limit(max_request_bytes))
+        .or(warp::get())
+        .unify();
+    let graphql = warp::path!("graphql").and(body_limit).and(graphql_filter);
 
-    let addr = addr
-        .to_socket_addrs()
-        .context(error::IOError {
-            msg: String::from("To Sock Addr"),
-        })?
-        .next()
-        .ok_or(error::Error::MiscError {
-            msg: String::from("Cannot resolve addr"),
-        })?;
+    // The Grafana SimpleJSON datasource protocol: GET / tests the connection.
+    let grafana_body = warp::body::content_length_limit(max_request_bytes).and(warp::body::json());
+    let grafana = warp::path("grafana").and(
+        warp::get()
+            .and(warp::path::end())
+            .map(warp::reply)
+            .or(warp::post()
+                .and(warp::path!("search"))
+                .and(grafana_body.clone())
+                .and(state.clone())
+                .and_then(grafana::search))
+            .or(warp::post()
+                .and(warp::path!("query"))
+                .and(grafana_body.clone())
+                .and(state.clone())
+                .and_then(grafana::query))
+            .or(warp::post()
+                .and(warp::path!("annotations"))
+                .and(grafana_body)
+                .and(state.clone())
+                .and_then(grafana::annotations)),
+    );
+
+    let coordinator = Arc::new(gql::coordinator());
+
+    // The gauge of the probe loop is only updated when asked for.
+    let metrics = warp::get()
+        .and(warp::path!("metrics"))
+        .and(state.clone())
+        .map(|context: gql::Context| {
+            background::loop_healthy(&context);
+            warp::reply::with_header(metrics::gather(), "content-type", prometheus::TEXT_FORMAT)
+        });
+
+    let health = warp::get()
+        .and(warp::path!("health"))
+        .and(state.clone())
+        .map(|context: gql::Context| {
+            if background::loop_healthy(&context) {
+                warp::reply::with_status("OK", http::StatusCode::OK)
+            } else {
+                warp::reply::with_status(
+                    "The background probe loop stalled",
+                    http::StatusCode::SERVICE_UNAVAILABLE,
+                )
+            }
+        });
+
+    let subscriptions = warp::path!("subscriptions")
+        .and(warp::ws())
+        .and(state)
+        .and(warp::any().map(move || coordinator.clone()))
+        .map(
+            |ws: warp::ws::Ws, context: gql::Context, coordinator: Arc<gql::Coordinator>| {
+                ws.on_upgrade(move |websocket| {
+                    let logger = context.logger.clone();
+                    juniper_warp::subscriptions::graphql_subscriptions(
+                        websocket,
+                        coordinator,
+                        context,
+                    )
+                    .map(move |res| {
+                        if let Err(err) = res {
+                            warn!(logger, "Websocket error: {}", err);
+                        }
+                    })
+                    .boxed()
+                })
+            },
+        )
+        .map(|reply| warp::reply::with_header(reply, "Sec-WebSocket-Protocol", "graphql-ws"));
+
+    let routes = graphql
+        .or(subscriptions)
+        .or(environments)
+        .or(metrics)
+        .or(health)
+        .or(grafana);
+
+    let base = base_path_filter(&base_path);
+
+    // The version is always served at /version, regardless of the base path.
+    let version = warp::get().and(warp::path!("version")).map(|| {
+        warp::reply::with_header(
+            env!("CARGO_PKG_VERSION"),
+            "cache-control",
+            STATIC_CACHE_CONTROL,
+        )
+    });
+
+    let routes = if disable_playground {
+        version
+            .or(base.and(routes))
+            .map(Reply::into_response)
+            .boxed()
+    } else {
+        version
+            .or(base.and(playground.or(routes)))
+            .map(Reply::into_response)
+            .boxed()
+    };
+
+    let service = warp::service(routes);
+    let request_logger = logger.clone();
+    let connection_service = move || {
+        let service = service.clone();
+        let logger = request_logger.clone();
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(move |req| {
+                with_request_logging(service.clone(), req, logger.clone(), max_request_bytes)
+            }))
+        }
+    };
+    let res = match listener {
+        Listener::Tcp(host, port) => {
+            let addr = (host.as_str(), port)
+                .to_socket_addrs()
+                .context(error::IOError {
+                    msg: String::from("To Sock Addr"),
+                })?
+                .next()
+                .ok_or(error::Error::MiscError {
+                    msg: String::from("Cannot resolve addr"),
+                })?;
+
+            info!(
+                logger.clone(),
+                "Serving Bragi Elasticsearch Probe on {}:{}",
+                addr.ip(),
+                addr.port()
+            );
+            hyper::Server::bind(&addr)
+                .serve(hyper::service::make_service_fn(move |_| {
+                    connection_service()
+                }))
+                .await
+        }
+        Listener::Unix(path) => {
+            remove_stale_socket(&path)?;
+            let mut unix_listener =
+                tokio::net::UnixListener::bind(&path).context(error::IOError {
+                    msg: format!("Could not listen on {}", path.display()),
+                })?;
+            info!(
+                logger.clone(),
+                "Serving Bragi Elasticsearch Probe on {}",
+                path.display()
+            );
+            let server = hyper::Server::builder(hyper::server::accept::from_stream(
+                unix_listener.incoming(),
+            ))
+            .serve(hyper::service::make_service_fn(move |_| {
+                connection_service()
+            }));
+            // The socket file outlives the listener, so we remove it ourselves when stopped.
+            let res = tokio::select! {
+                res = server => res,
+                _ = shutdown_signal() => {
+                    info!(logger, "Shutting down");
+                    Ok(())
+                }
+            };
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!(logger, "Could not remove {}: {}", path.display(), err);
+            }
+            res
+        }
+    };
+    res.map_err(|err| error::Error::MiscError {
+        msg: format!("Server error: {}", err),
+    })
+}
+
+// Remove the socket file left at the path by a previous run which did not stop cleanly, so that
+// we can listen on it again. A socket still served by another process, or a file which is not a
+// socket, is left alone, and we fail instead.
+fn remove_stale_socket(path: &Path) -> Result<(), error::Error> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(error::Error::MiscError {
+            msg: format!("Could not listen on {}: not a socket", path.display()),
+        });
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(error::Error::MiscError {
+            msg: format!(
+                "Could not listen on {}: another server is listening on it",
+                path.display()
+            ),
+        });
+    }
+    std::fs::remove_file(path).context(error::IOError {
+        msg: format!("Could not remove the stale socket {}", path.display()),
+    })
+}
+
+// Resolves when the server is asked to stop, with Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM can be handled");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Make sure each request has an X-Request-Id header, using the client's one or a new UUID, so
+/// that the routes can log it, and echo it in the response.
+async fn with_request_id<S>(
+    mut service: S,
+    mut req: http::Request<hyper::Body>,
+) -> Result<http::Response<hyper::Body>, Infallible>
+where
+    S: hyper::service::Service<
+        http::Request<hyper::Body>,
+        Response = http::Response<hyper::Body>,
+        Error = Infallible,
+    >,
+{
+    let id = match req.headers().get(REQUEST_ID) {
+        Some(id) => id.clone(),
+        None => {
+            let id = http::HeaderValue::from_str(&Uuid::new_v4().to_string())
+                .expect("a UUID is a valid header value");
+            req.headers_mut().insert(REQUEST_ID, id.clone());
+            id
+        }
+    };
+    let mut resp = service.call(req).await?;
+    resp.headers_mut().insert(REQUEST_ID, id);
+    Ok(resp)
+}
+
+// Probe all environments once, and fail with the list of those which are not available.
+async fn check_all_healthy(context: &gql::Context) -> Result<(), error::Error> {
+    let problems = environment::list_problems(context).await?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let problems: Vec<String> = problems
+        .iter()
+        .map(|problem| {
+            format!(
+                "{} ({:?}: {})",
+                problem.label,
+                problem.status,
+                problem.error.as_deref().unwrap_or("unknown error")
+            )
+        })
+        .collect();
+    Err(error::Error::MiscError {
+        msg: format!("Environments not available: {}", problems.join(", ")),
+    })
+}
+
+// Probe all environments once, so that the first queries find warm connections, and the latest
+// results of environments probed in the background. A failure only makes the warmup useless.
+async fn warmup(context: &gql::Context) {
+    info!(context.logger, "Warming up");
+    let start = Instant::now();
+    match environment::list_environments(context, Default::default()).await {
+        Ok(_) => info!(
+            context.logger,
+            "Warmed up in {} ms",
+            start.elapsed().as_millis()
+        ),
+        Err(err) => warn!(context.logger, "Could not warm up: {}", err),
+    }
+}
+
+// Turn the base path given on the command line into either an empty string, or a path
+// starting with '/' and without a trailing '/' (eg 'besp/' => '/besp')
+fn normalize_base_path(base_path: &str) -> String {
+    base_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| format!("/{}", segment))
+        .collect()
+}
 
+// A filter matching each segment of the (normalized) base path.
+fn base_path_filter(base_path: &str) -> BoxedFilter<()> {
+    base_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(String::from(segment))).boxed()
+        })
+}
+
+/// Log each request once answered, with its method, path, status and duration, its request id,
+/// and the GraphQL operation name, if any. Requests are logged here rather than by the routes, so
+/// that those the routes reject (eg 404, 405 or 413) are logged too.
+async fn with_request_logging<S>(
+    service: S,
+    req: http::Request<hyper::Body>,
+    logger: Logger,
+    max_request_bytes: u64,
+) -> Result<http::Response<hyper::Body>, Infallible>
+where
+    S: hyper::service::Service<
+        http::Request<hyper::Body>,
+        Response = http::Response<hyper::Body>,
+        Error = Infallible,
+    >,
+{
+    let start = Instant::now();
+    let method = req.method().clone();
+    let path = String::from(req.uri().path());
+    let (req, operation) = operation_name(req, max_request_bytes).await;
+    let resp = with_request_id(service, req).await?;
+    let duration = start.elapsed().as_millis() as u64;
+    let request_id = resp
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or("");
     info!(
-        logger.clone(),
-        "Serving Bragi Elasticsearch Probe on {}:{}",
-        addr.ip(),
-        addr.port()
+        logger,
+        "{} {} {} ({}ms)",
+        method,
+        path,
+        resp.status().as_u16(),
+        duration;
+        "method" => method.as_str(),
+        "path" => path.as_str(),
+        "status" => resp.status().as_u16(),
+        "duration_ms" => duration,
+        "operation" => operation.as_deref().unwrap_or(""),
+        "request_id" => request_id
     );
-    warp::serve(routes).run(addr).await;
+    Ok(resp)
+}
 
-    Ok(())
+#[derive(Debug, Deserialize)]
+struct OperationBody {
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+}
+
+// The body of a GraphQL POST request: a single request, or a batch of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OperationBatch {
+    Single(OperationBody),
+    Batch(Vec<OperationBody>),
+}
+
+// Extract the GraphQL operation name of the request, from its query string, or from its JSON
+// body, which is read and handed back with the request. Bodies without a length, or too large,
+// are left alone, since the routes refuse them anyway. The operations of a batch are joined with
+// commas.
+async fn operation_name(
+    req: http::Request<hyper::Body>,
+    max_request_bytes: u64,
+) -> (http::Request<hyper::Body>, Option<String>) {
+    if let Some(query) = req.uri().query() {
+        let operation = url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "operationName" || key == "operation_name")
+            .map(|(_, name)| name.into_owned());
+        if operation.is_some() {
+            return (req, operation);
+        }
+    }
+    let length = req
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    let json = req
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.starts_with("application/json"))
+        .unwrap_or(false);
+    let graphql = req.method() == http::Method::POST && req.uri().path().ends_with("/graphql");
+    match length {
+        Some(length) if graphql && json && length <= max_request_bytes => {}
+        _ => return (req, None),
+    }
+    let (parts, body) = req.into_parts();
+    // The client went away while sending its body, the request fails either way.
+    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+    let operation = match serde_json::from_slice::<OperationBatch>(&body) {
+        Ok(OperationBatch::Single(request)) => request.operation_name,
+        Ok(OperationBatch::Batch(requests)) => {
+            let names: Vec<String> = requests
+                .into_iter()
+                .filter_map(|request| request.operation_name)
+                .collect();
+            Some(names.join(",")).filter(|names| !names.is_empty())
+        }
+        Err(_) => None,
+    };
+    (
+        http::Request::from_parts(parts, hyper::Body::from(body)),
+        operation,
+    )
 }
 
 /// Create a filter that replies with an HTML page containing GraphQL Playground. This does not handle routing, so you can mount it on any endpoint.
+/// The page is rendered once, when the filter is created.
 pub fn playground_filter(
-    graphql_endpoint_url: &'static str,
-    subscriptions_endpoint_url: Option<&'static str>,
+    graphql_endpoint_url: &str,
+    subscriptions_endpoint_url: Option<&str>,
 ) -> warp::filters::BoxedFilter<(http::Response<Vec<u8>>,)> {
+    let page = juniper::http::playground::playground_source(
+        graphql_endpoint_url,
+        subscriptions_endpoint_url,
+    )
+    .into_bytes();
     warp::any()
-        .map(move || playground_response(graphql_endpoint_url, subscriptions_endpoint_url))
+        .map(move || playground_response(page.clone()))
         .boxed()
 }
 
-fn playground_response(
-    graphql_endpoint_url: &'static str,
-    subscriptions_endpoint_url: Option<&'static str>,
-) -> http::Response<Vec<u8>> {
+fn playground_response(page: Vec<u8>) -> http::Response<Vec<u8>> {
     http::Response::builder()
         .header("content-type", "text/html;charset=utf-8")
-        .body(
-            juniper::http::playground::playground_source(
-                graphql_endpoint_url,
-                subscriptions_endpoint_url,
-            )
-            .into_bytes(),
-        )
+        .header("cache-control", STATIC_CACHE_CONTROL)
+        .body(page)
         .expect("response is valid")
 }
@@ -0,0 +1,98 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_gauge_vec, register_histogram, register_int_counter,
+    register_int_counter_vec, register_int_gauge, Encoder, GaugeVec, Histogram, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    /// Distribution of the number of documents per index, across all environments.
+    /// Buckets go from 10 to 100M documents.
+    pub static ref INDEX_DOCUMENT_COUNT: Histogram = register_histogram!(
+        "besp_index_document_count",
+        "Number of documents in each probed index",
+        exponential_buckets(10.0, 10.0, 8).unwrap()
+    )
+    .unwrap();
+
+    /// Number of failed probes, by environment and kind of error.
+    pub static ref PROBE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "besp_probe_errors_total",
+        "Number of failed probes",
+        &["env", "kind"]
+    )
+    .unwrap();
+
+    /// Number of changes of the status of environments, by environment and new status.
+    pub static ref STATUS_CHANGES: IntCounterVec = register_int_counter_vec!(
+        "besp_status_changes_total",
+        "Number of changes of the status of environments",
+        &["env", "status"]
+    )
+    .unwrap();
+
+    /// Number of probes currently running.
+    pub static ref PROBES_IN_FLIGHT: IntGauge =
+        register_int_gauge!("besp_probes_in_flight", "Number of probes currently running").unwrap();
+
+    /// Number of probes dropped before they completed, eg because the client disconnected.
+    pub static ref PROBES_CANCELLED: IntCounter = register_int_counter!(
+        "besp_probes_cancelled_total",
+        "Number of probes cancelled before they completed"
+    )
+    .unwrap();
+
+    /// 1 while the background probe loop ticks, 0 once it stalled or died.
+    pub static ref PROBE_LOOP_HEALTHY: IntGauge = register_int_gauge!(
+        "besp_probe_loop_healthy",
+        "Whether the background probe loop is running"
+    )
+    .unwrap();
+
+    /// Moving average of the duration of probes, by environment.
+    pub static ref PROBE_LATENCY_EWMA: GaugeVec = register_gauge_vec!(
+        "besp_probe_latency_ewma_ms",
+        "Exponential moving average of the duration of probes, in milliseconds",
+        &["env"]
+    )
+    .unwrap();
+}
+
+/// Counts a probe in besp_probes_in_flight for as long as it lives, so that the probe is
+/// no longer counted when it returns early, fails or panics. A probe dropped before it is
+/// finished, other than by a panic, is counted in besp_probes_cancelled_total.
+pub struct InFlightGuard {
+    finished: bool,
+}
+
+impl InFlightGuard {
+    /// Count a new probe.
+    pub fn start() -> Self {
+        PROBES_IN_FLIGHT.inc();
+        InFlightGuard { finished: false }
+    }
+
+    /// Mark the probe as finished: dropping it is no longer a cancellation.
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        PROBES_IN_FLIGHT.dec();
+        if !self.finished && !std::thread::panicking() {
+            PROBES_CANCELLED.inc();
+        }
+    }
+}
+
+/// Render all the registered metrics in the prometheus text format.
+pub fn gather() -> String {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("metrics can be encoded");
+    String::from_utf8(buffer).expect("metrics are valid utf8")
+}
@@ -0,0 +1,460 @@
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error;
+
+/// The number of redirects reqwest follows by default
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// Time after which a connection which could not be opened fails
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An entry of the configuration file (env.json): either an environment, or a template
+/// describing many environments.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EnvEntry {
+    Env(Env),
+    Template(EnvTemplate),
+}
+
+/// Environments whose url only differ by their name
+#[derive(Debug, Clone, Deserialize)]
+pub struct EnvTemplate {
+    /// The url of the environments, where {name} is replaced by the name of each environment
+    /// (eg https://bragi-{name}.example.com)
+    pub url_template: String,
+    pub names: Vec<String>,
+}
+
+/// An environment, as described in the configuration file (env.json)
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Env {
+    pub env: String,
+    pub url: String,
+    /// The list of coverages we expect to find in this environment. Indices with a coverage
+    /// outside of this list are reported in the warnings.
+    #[serde(default)]
+    pub known_coverages: Option<Vec<String>>,
+    /// Only report indices with one of these coverages. An empty or absent list means all
+    /// coverages are reported.
+    #[serde(default)]
+    pub coverage_allowlist: Option<Vec<String>>,
+    /// Maximum age (in seconds) of the newest index before the environment is reported as stale.
+    #[serde(default)]
+    pub max_index_age: Option<i64>,
+    /// Number of indices the environment should serve. With fewer indices, the environment is
+    /// reported as degraded.
+    #[serde(default)]
+    pub expected_index_count: Option<usize>,
+    /// Warn about indices without any document, which may come from a failed import.
+    #[serde(default)]
+    pub flag_empty_indices: bool,
+    /// With flag_empty_indices, report the environment as degraded when an index is empty.
+    #[serde(default)]
+    pub degrade_on_empty_indices: bool,
+    /// The version of bragi the environment should run. Another version is reported in the
+    /// warnings, and with versionMatchesExpected.
+    #[serde(default)]
+    pub expected_version: Option<String>,
+    /// Format of the date segment of index names, overriding --date-format.
+    #[serde(default)]
+    pub date_format: Option<String>,
+    /// Format of the time segment of index names, overriding --time-format.
+    #[serde(default)]
+    pub time_format: Option<String>,
+    /// Interval (in seconds) between background probes of this environment, overriding
+    /// --probe-interval.
+    #[serde(default)]
+    pub interval: Option<u64>,
+    /// Glob patterns of indices to ignore (eg .geonames*)
+    #[serde(default)]
+    pub index_exclude_patterns: Vec<String>,
+    /// The character separating the segments of index names (eg '-' for mimir-addr-fr-20240101),
+    /// '_' by default.
+    #[serde(default)]
+    pub index_delimiter: Option<char>,
+    /// The coverage searched by the autocomplete probe, overriding --probe-query-coverage.
+    #[serde(default)]
+    pub search_coverage: Option<String>,
+    /// The team owning the environment, reported with its results and status changes so that
+    /// alerts reach the right people.
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Free-form tags (eg prod, dev), weighting the environment in the fleet health with
+    /// --tag-weight.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The SSH bastion through which bragi and elasticsearch are reached, if they are not
+    /// reachable directly.
+    #[serde(default)]
+    pub tunnel: Option<TunnelConfig>,
+    /// Accept invalid TLS certificates (eg self-signed) from this environment. Never use this in
+    /// production.
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+/// An SSH bastion, through which the probes of an environment send their requests
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct TunnelConfig {
+    /// The address of the bastion, as host or host:port (port 22 by default)
+    pub bastion: String,
+    pub user: String,
+    /// The private key used to log into the bastion
+    pub key: PathBuf,
+    /// The file holding the key of the bastion (~/.ssh/known_hosts by default)
+    #[serde(default)]
+    pub known_hosts: Option<PathBuf>,
+}
+
+/// The shape of the response to 'bragi/status'
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BragiApiVersion {
+    /// { "version": ..., "es": ..., "status": ... }
+    V1,
+    /// { "bragi": { "version": ... }, "elasticsearch": { "url": ..., "status": ... } }
+    V2,
+}
+
+impl FromStr for BragiApiVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(BragiApiVersion::V1),
+            "v2" => Ok(BragiApiVersion::V2),
+            _ => Err(format!("Unknown bragi API version {} (expected v1 or v2)", s)),
+        }
+    }
+}
+
+/// How queries use the results of previous probes, for environments which are not probed in the
+/// background
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheMode {
+    /// Always probe
+    Fresh,
+    /// Serve the latest result, and refresh it in the background once older than --cache-ttl
+    StaleWhileRevalidate,
+}
+
+impl FromStr for CacheMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fresh" => Ok(CacheMode::Fresh),
+            "swr" => Ok(CacheMode::StaleWhileRevalidate),
+            _ => Err(format!("Unknown cache mode {} (expected fresh or swr)", s)),
+        }
+    }
+}
+
+/// A target probes must not reach (--deny-cidr), so that the probe can't be used against
+/// internal endpoints, eg the cloud metadata at 169.254.169.254
+#[derive(Debug, Clone, PartialEq)]
+pub enum DenyRule {
+    /// A range of addresses, as its first address and the length of its prefix
+    Cidr(IpAddr, u8),
+    /// A host name, denied whatever it resolves to
+    Host(String),
+}
+
+impl DenyRule {
+    /// The loopback and link-local ranges, always denied to probeUrl
+    pub fn defaults() -> Vec<DenyRule> {
+        vec![
+            DenyRule::Cidr(Ipv4Addr::new(127, 0, 0, 0).into(), 8),
+            DenyRule::Cidr(Ipv4Addr::new(169, 254, 0, 0).into(), 16),
+            DenyRule::Cidr(Ipv6Addr::LOCALHOST.into(), 128),
+            DenyRule::Cidr(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0).into(), 10),
+        ]
+    }
+
+    /// Whether the address is in the denied range. IPv4 addresses mapped to IPv6 are checked
+    /// as IPv4 addresses.
+    pub fn denies_addr(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            IpAddr::V4(_) => addr,
+        };
+        match (self, addr) {
+            (DenyRule::Cidr(IpAddr::V4(net), prefix), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(addr) & mask
+            }
+            (DenyRule::Cidr(IpAddr::V6(net), prefix), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                u128::from(*net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the host name is denied
+    pub fn denies_host(&self, host: &str) -> bool {
+        match self {
+            DenyRule::Host(denied) => denied.eq_ignore_ascii_case(host),
+            DenyRule::Cidr(..) => false,
+        }
+    }
+}
+
+impl FromStr for DenyRule {
+    type Err = String;
+
+    /// Parse a range (eg 10.0.0.0/8), an address, denied alone, or a host name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        match (IpAddr::from_str(addr), prefix) {
+            (Ok(addr), prefix) => {
+                let bits = if addr.is_ipv4() { 32 } else { 128 };
+                match prefix.map(u8::from_str) {
+                    None => Ok(DenyRule::Cidr(addr, bits)),
+                    Some(Ok(prefix)) if prefix <= bits => Ok(DenyRule::Cidr(addr, prefix)),
+                    _ => Err(format!("Invalid prefix length in {}", s)),
+                }
+            }
+            (Err(_), None) if !s.is_empty() => Ok(DenyRule::Host(s.to_lowercase())),
+            (Err(_), _) => Err(format!("{} is neither a range, an address nor a host", s)),
+        }
+    }
+}
+
+// Whether the url targets a denied host or address, without resolving its host: this is all
+// we can check when following a redirect.
+fn denies_url(deny: &[DenyRule], url: &reqwest::Url) -> bool {
+    match url.host() {
+        Some(url::Host::Domain(host)) => deny.iter().any(|rule| rule.denies_host(host)),
+        Some(url::Host::Ipv4(addr)) => deny.iter().any(|rule| rule.denies_addr(addr.into())),
+        Some(url::Host::Ipv6(addr)) => deny.iter().any(|rule| rule.denies_addr(addr.into())),
+        None => false,
+    }
+}
+
+/// Options given on the command line.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Lowercase and trim the coverage extracted from index names.
+    pub normalize_coverage: bool,
+    /// Do not serve the GraphQL playground.
+    pub disable_playground: bool,
+    /// Reject GraphQL queries on the schema, so that it can't be enumerated.
+    pub disable_introspection: bool,
+    /// Refuse to start unless all environments are available on a first probe.
+    pub require_all_healthy: bool,
+    /// Probe all environments once before accepting connections.
+    pub warmup: bool,
+    /// The file or http(s) url the environments are read from
+    pub config: String,
+    /// Only probe the environments with these names
+    pub only: Option<Vec<String>>,
+    /// Reload the environments when env.json changes
+    pub watch_config: bool,
+    /// Address of a SOCKS5 proxy used for all probe requests (eg socks5://bastion:1080)
+    pub socks_proxy: Option<String>,
+    /// Format of the date segment of index names (eg %Y%m%d)
+    pub date_format: String,
+    /// Format of the time segment of index names (eg %H%M%S)
+    pub time_format: String,
+    /// Path prefix for all routes (eg /besp)
+    pub base_path: String,
+    /// Maximum number of idle connections kept per host
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Time (in seconds) after which idle connections are closed
+    pub pool_idle_timeout: Option<u64>,
+    /// Time (in seconds) after which a request which did not complete fails
+    pub request_timeout: u64,
+    /// Interval (in seconds) of TCP keepalive probes, if enabled
+    pub tcp_keepalive: Option<u64>,
+    /// Disable Nagle's algorithm, so that small requests are sent at once (reqwest's default when
+    /// not set)
+    pub tcp_nodelay: Option<bool>,
+    /// Interval (in seconds) between background probes of all environments. When set, queries
+    /// return the latest background probe results.
+    pub probe_interval: Option<u64>,
+    /// Maximum delay (in seconds) before the first background probe of each environment, to
+    /// spread the probes over time
+    pub probe_jitter: Option<u64>,
+    /// Seed of the random delays added by --probe-jitter
+    pub probe_jitter_seed: Option<u64>,
+    /// How queries use the results of previous probes
+    pub cache_mode: CacheMode,
+    /// Age (in seconds) after which a cached result is refreshed (--cache-mode swr)
+    pub cache_ttl: u64,
+    /// Time (in seconds) after --cache-ttl during which the latest successful result is served
+    /// instead of a failed one (--cache-mode swr)
+    pub max_stale: u64,
+    /// Maximum number of requests sent by each cycle of the background probes
+    pub request_budget: Option<u64>,
+    /// Number of times the first request of a probe is retried when it can't connect
+    pub probe_retries: u32,
+    /// Maximum number of retries across all probes in each cycle of the probe loop
+    pub retry_budget: Option<u64>,
+    /// Probes of an environment taking longer than this (in milliseconds) are logged
+    pub slow_probe_threshold: Option<u64>,
+    /// Percentage of documents an index can lose between two probes before we warn about it
+    pub count_drop_warning: u32,
+    /// The shape of bragi's status response. When not given, all known shapes are tried.
+    pub bragi_api_version: Option<BragiApiVersion>,
+    /// User agent of all probe requests
+    pub user_agent: String,
+    /// Use HTTP/2 without negotiation for all probe requests
+    pub http2_prior_knowledge: bool,
+    /// Maximum number of redirects followed by probe requests (0 to report redirects instead)
+    pub max_redirects: Option<usize>,
+    /// Maximum size of a response body we accept from bragi or elasticsearch
+    pub max_response_bytes: Option<u64>,
+    /// Maximum size of the body of a GraphQL request
+    pub max_request_bytes: u64,
+    /// PEM file with the client certificate presented to TLS servers requiring one
+    pub client_cert: Option<String>,
+    /// PEM file with the private key of the client certificate
+    pub client_key: Option<String>,
+    /// Token clients present as 'Authorization: Bearer <token>' to probe arbitrary urls. Without
+    /// it, nobody can.
+    pub admin_token: Option<String>,
+    /// PEM file with an additional CA certificate to trust
+    pub ca_cert: Option<String>,
+    /// Accept invalid TLS certificates from all environments
+    pub danger_accept_invalid_certs: bool,
+    /// Hosts and ranges of addresses probes must not reach
+    pub deny: Vec<DenyRule>,
+    /// Addresses used instead of DNS for some hosts, for all probe requests
+    pub resolve: Vec<(String, IpAddr)>,
+    /// Latitude and longitude bragi is asked to reverse geocode after its status, if any
+    pub reverse_coordinates: Option<(f64, f64)>,
+    /// Count the segments of each index, with an extra (heavy) request to elasticsearch
+    pub segment_counts: bool,
+    /// Text bragi is asked to autocomplete after its status, if any
+    pub search_query: Option<String>,
+    /// The coverage searched by the autocomplete probe, unless the environment gives its own
+    pub probe_query_coverage: Option<String>,
+    /// Weight of the environments with each tag in the fleet health. An environment weighs as
+    /// much as its heaviest tag, or 1 without a weighted tag.
+    pub tag_weights: Vec<(String, f64)>,
+    /// Names of the metrics of bragi reported with each environment (none by default)
+    pub bragi_metrics: Vec<String>,
+    /// Path of bragi's metrics endpoint, relative to its url
+    pub bragi_metrics_path: String,
+}
+
+impl Settings {
+    /// Build the HTTP client shared by all the probes. With `accept_invalid_certs`, the client
+    /// does not verify the certificates of TLS servers.
+    pub fn client(&self, accept_invalid_certs: bool) -> Result<reqwest::Client, error::Error> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(self.user_agent.as_str())
+            .danger_accept_invalid_certs(accept_invalid_certs || self.danger_accept_invalid_certs);
+        if let Some(proxy) = &self.socks_proxy {
+            let proxy = reqwest::Proxy::all(proxy).context(error::ClientError {
+                msg: format!("Invalid SOCKS proxy {}", proxy),
+            })?;
+            builder = builder.proxy(proxy);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if !self.deny.is_empty() {
+            // Redirects could otherwise lead to a denied target.
+            let deny = self.deny.clone();
+            let max = self.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS);
+            builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if max == 0 {
+                    attempt.stop()
+                } else if denies_url(&deny, attempt.url()) {
+                    let msg = format!("redirect to {} denied by --deny-cidr", attempt.url());
+                    attempt.error(msg)
+                } else if attempt.previous().len() > max {
+                    attempt.error("too many redirects")
+                } else {
+                    attempt.follow()
+                }
+            }));
+        } else if let Some(max) = self.max_redirects {
+            let policy = match max {
+                0 => reqwest::redirect::Policy::none(),
+                max => reqwest::redirect::Policy::limited(max),
+            };
+            builder = builder.redirect(policy);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(Duration::from_secs(timeout));
+        }
+        // A server which never answers would otherwise hold its probe forever.
+        let timeout = Duration::from_secs(self.request_timeout);
+        builder = builder
+            .timeout(timeout)
+            .connect_timeout(timeout.min(CONNECT_TIMEOUT));
+        if let Some(interval) = self.tcp_keepalive {
+            builder = builder.tcp_keepalive(Duration::from_secs(interval));
+        }
+        if let Some(nodelay) = self.tcp_nodelay {
+            builder = builder.tcp_nodelay_(nodelay);
+        }
+        if let Some(identity) = self.identity()? {
+            // PEM identities are only supported by the rustls backend.
+            builder = builder.use_rustls_tls().identity(identity);
+        }
+        if let Some(path) = &self.ca_cert {
+            let pem = read_file(path)?;
+            let cert = reqwest::Certificate::from_pem(&pem).context(error::ClientError {
+                msg: format!("Invalid CA certificate {}", path),
+            })?;
+            builder = builder.add_root_certificate(cert);
+        }
+        builder.build().context(error::ClientError {
+            msg: String::from("Could not create the probe client"),
+        })
+    }
+
+    // Load the client certificate and its key, which must be given together.
+    fn identity(&self) -> Result<Option<reqwest::Identity>, error::Error> {
+        match (&self.client_cert, &self.client_key) {
+            (None, None) => Ok(None),
+            (Some(cert), Some(key)) => {
+                let mut pem = read_file(cert)?;
+                pem.push(b'\n');
+                pem.extend(read_file(key)?);
+                reqwest::Identity::from_pem(&pem)
+                    .context(error::ClientError {
+                        msg: format!("Invalid client certificate {} or key {}", cert, key),
+                    })
+                    .map(Some)
+            }
+            _ => Err(error::Error::MiscError {
+                msg: String::from("--client-cert and --client-key must be given together"),
+            }),
+        }
+    }
+
+    /// The address --resolve gives for the host of the url, if any. HTTPS urls are not
+    /// overridden: the request would go to the address, whose certificate can't match the host.
+    pub fn resolved(&self, url: &reqwest::Url) -> Option<IpAddr> {
+        if url.scheme() != "http" {
+            return None;
+        }
+        let host = url.host_str()?;
+        self.resolve
+            .iter()
+            .find(|(name, _)| name == host)
+            .map(|(_, addr)| *addr)
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, error::Error> {
+    std::fs::read(path).context(error::IOError {
+        msg: format!("Could not read {}", path),
+    })
+}
@@ -0,0 +1,488 @@
+use mio::unix::{EventedFd, UnixReady};
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use snafu::ResultExt;
+use ssh2::{BlockDirections, Channel, CheckResult, KnownHostFileKind, Session};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::error;
+use crate::settings::TunnelConfig;
+
+/// How long the tunnel waits for something to forward before checking whether it was closed
+const WAKE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often (in seconds) the session is kept alive while idle, between probes
+const KEEPALIVE_INTERVAL: u32 = 30;
+
+/// How long a local connection has to tell where it goes
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The poll tokens of the local listener and of the session. Connections get the next ones.
+const LISTENER: Token = Token(0);
+const SESSION: Token = Token(1);
+
+/// An SSH tunnel through a bastion, served as a local SOCKS5 proxy (like `ssh -D`): requests
+/// keep their urls, and the bastion connects to their hosts. The tunnel is closed when dropped,
+/// or when the bastion hangs up.
+#[derive(Debug)]
+pub struct Tunnel {
+    closed: Arc<AtomicBool>,
+    port: u16,
+}
+
+impl Tunnel {
+    /// Log into the bastion, and serve the tunnel from a background thread.
+    pub async fn open(config: &TunnelConfig) -> Result<Tunnel, error::Error> {
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || connect(&config))
+            .await
+            .map_err(|err| error::Error::MiscError {
+                msg: format!("Could not open SSH tunnel: {}", err),
+            })?
+    }
+
+    /// The url of the proxy, for the client. Host names are resolved by the bastion.
+    pub fn proxy(&self) -> String {
+        format!("socks5h://{}:{}", Ipv4Addr::LOCALHOST, self.port)
+    }
+
+    /// Whether the tunnel still forwards connections.
+    pub fn is_open(&self) -> bool {
+        !self.closed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+// Log into the bastion, and listen on a local port, from which connections are forwarded
+// through the bastion by a background thread.
+fn connect(config: &TunnelConfig) -> Result<Tunnel, error::Error> {
+    let (bastion_host, bastion_port) = bastion_address(&config.bastion)?;
+    let tcp =
+        TcpStream::connect((bastion_host.as_str(), bastion_port)).context(error::IOError {
+            msg: format!("Could not reach bastion {}", config.bastion),
+        })?;
+    let mut session = Session::new().context(error::SSHError {
+        msg: String::from("Could not create SSH session"),
+    })?;
+    session.set_tcp_stream(tcp);
+    session.handshake().context(error::SSHError {
+        msg: format!("SSH handshake with {} failed", config.bastion),
+    })?;
+    check_host_key(&session, config, &bastion_host, bastion_port)?;
+    session
+        .userauth_pubkey_file(&config.user, None, &config.key, None)
+        .context(error::SSHError {
+            msg: format!("Could not log into {} as {}", config.bastion, config.user),
+        })?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).context(error::IOError {
+        msg: String::from("Could not listen for the SSH tunnel"),
+    })?;
+    let port = listener
+        .local_addr()
+        .context(error::IOError {
+            msg: String::from("Could not listen for the SSH tunnel"),
+        })?
+        .port();
+    listener.set_nonblocking(true).context(error::IOError {
+        msg: String::from("Could not listen for the SSH tunnel"),
+    })?;
+    // The session is shared by the connections, which must not block each other.
+    session.set_blocking(false);
+    session.set_keepalive(false, KEEPALIVE_INTERVAL);
+    let closed = Arc::new(AtomicBool::new(false));
+    let serving = closed.clone();
+    thread::spawn(move || {
+        // Whatever stopped the tunnel, the next probe opens a new one.
+        let _ = serve(listener, session, &serving);
+        serving.store(true, Ordering::Relaxed);
+    });
+    Ok(Tunnel { closed, port })
+}
+
+// Split the address of a bastion into its host and port, 22 by default.
+fn bastion_address(bastion: &str) -> Result<(String, u16), error::Error> {
+    match bastion.rsplit_once(':') {
+        Some((host, port)) => port
+            .parse()
+            .map(|port| (String::from(host), port))
+            .map_err(|_| error::Error::MiscError {
+                msg: format!("Invalid bastion address {}", bastion),
+            }),
+        None => Ok((String::from(bastion), 22)),
+    }
+}
+
+// Check the key of the bastion against the known hosts (~/.ssh/known_hosts by default), so that
+// we don't hand our credentials to an impostor.
+fn check_host_key(
+    session: &Session,
+    config: &TunnelConfig,
+    host: &str,
+    port: u16,
+) -> Result<(), error::Error> {
+    let file = match &config.known_hosts {
+        Some(file) => file.clone(),
+        None => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".ssh/known_hosts"),
+    };
+    let mut known_hosts = session.known_hosts().context(error::SSHError {
+        msg: String::from("Could not initialize known hosts"),
+    })?;
+    known_hosts
+        .read_file(&file, KnownHostFileKind::OpenSSH)
+        .context(error::SSHError {
+            msg: format!("Could not read known hosts from {}", file.display()),
+        })?;
+    let key = session.host_key().map(|(key, _)| key);
+    match key.map(|key| known_hosts.check_port(host, port, key)) {
+        Some(CheckResult::Match) => Ok(()),
+        _ => Err(error::Error::MiscError {
+            msg: format!(
+                "The key of bastion {} is not in {}",
+                config.bastion,
+                file.display()
+            ),
+        }),
+    }
+}
+
+// A local connection, and the channel forwarding it through the bastion
+struct Connection {
+    local: TcpStream,
+    channel: Channel,
+    token: Token,
+}
+
+// What forwarding a connection did
+enum Forwarded {
+    Data,
+    Nothing,
+    Closed,
+}
+
+// Accept local connections, and forward them until the tunnel is closed. The connections share
+// the SSH session, whose reads for one channel may receive the data of another one, so they are
+// all forwarded from this single thread, which waits for any of the sockets when none of them
+// has anything to forward.
+fn serve(listener: TcpListener, session: Session, closed: &AtomicBool) -> io::Result<()> {
+    let poll = Poll::new()?;
+    let mut events = Events::with_capacity(64);
+    let session_fd = session.as_raw_fd();
+    poll.register(
+        &EventedFd(&listener.as_raw_fd()),
+        LISTENER,
+        Ready::readable(),
+        PollOpt::level(),
+    )?;
+    poll.register(
+        &EventedFd(&session_fd),
+        SESSION,
+        Ready::empty(),
+        PollOpt::level(),
+    )?;
+    let mut connections: Vec<Connection> = Vec::new();
+    let mut next_token = SESSION.0 + 1;
+    let mut buf = [0; 16 * 1024];
+    while !closed.load(Ordering::Relaxed) {
+        let mut idle = true;
+        match listener.accept() {
+            Ok((local, _)) => {
+                // A failed connection shows up as a failed request in the probe.
+                if let Ok(connection) = open(local, &session, Token(next_token), closed) {
+                    poll.register(
+                        &EventedFd(&connection.local.as_raw_fd()),
+                        connection.token,
+                        Ready::readable(),
+                        PollOpt::level(),
+                    )?;
+                    connections.push(connection);
+                    next_token += 1;
+                }
+                idle = false;
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        let mut i = 0;
+        while i < connections.len() {
+            match forward(&mut connections[i], &session, &mut buf) {
+                Ok(Forwarded::Data) => idle = false,
+                Ok(Forwarded::Nothing) => {}
+                Ok(Forwarded::Closed) | Err(_) => {
+                    let mut connection = connections.swap_remove(i);
+                    poll.deregister(&EventedFd(&connection.local.as_raw_fd()))?;
+                    // Closing is best effort: the channel is freed when dropped anyway.
+                    let _ = connection.channel.close();
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        // The session outlives the probes, so it must not be dropped by the bastion while idle.
+        session.keepalive_send()?;
+        // Data already received by the session must be forwarded before waiting for more.
+        let buffered = connections.iter().any(|connection| {
+            connection.channel.read_window().available > 0 || connection.channel.eof()
+        });
+        if idle && !buffered {
+            // Only the channels read what the bastion sends, so without any, the session is only
+            // watched for the bastion hanging up.
+            let interest = if connections.is_empty() {
+                Ready::empty()
+            } else {
+                session_interest(&session)
+            };
+            poll.reregister(&EventedFd(&session_fd), SESSION, interest, PollOpt::level())?;
+            poll.poll(&mut events, Some(WAKE_INTERVAL))?;
+            let hung_up = events.iter().any(|event| {
+                let ready = UnixReady::from(event.readiness());
+                event.token() == SESSION && (ready.is_hup() || ready.is_error())
+            });
+            if hung_up {
+                return Err(io::ErrorKind::ConnectionAborted.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Read where a local connection goes (a SOCKS5 CONNECT request), and open a channel to it
+// through the bastion.
+fn open(
+    mut local: TcpStream,
+    session: &Session,
+    token: Token,
+    closed: &AtomicBool,
+) -> io::Result<Connection> {
+    local.set_nonblocking(false)?;
+    local.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let (host, port) = handshake(&mut local)?;
+    let channel = loop {
+        match session.channel_direct_tcpip(&host, port, None) {
+            Ok(channel) => break channel,
+            Err(err) => {
+                let err = io::Error::from(err);
+                if err.kind() != io::ErrorKind::WouldBlock || closed.load(Ordering::Relaxed) {
+                    local.write_all(&socks_reply(1))?;
+                    return Err(err);
+                }
+                wait_for(session.as_raw_fd(), session_interest(session))?;
+            }
+        }
+    };
+    local.write_all(&socks_reply(0))?;
+    local.set_read_timeout(None)?;
+    local.set_nonblocking(true)?;
+    Ok(Connection {
+        local,
+        channel,
+        token,
+    })
+}
+
+// Read the request of a SOCKS5 client, and return where it connects to, or reply that the
+// request is not supported.
+fn handshake<S: Read + Write>(local: &mut S) -> io::Result<(String, u16)> {
+    match socks_request(local)? {
+        Some(target) => Ok(target),
+        None => {
+            // The command or the address type is not supported.
+            local.write_all(&socks_reply(7))?;
+            Err(io::ErrorKind::InvalidInput.into())
+        }
+    }
+}
+
+// Read the greeting of a SOCKS5 client, accept it without authentication, and return the host
+// and port of its CONNECT request, if it is one.
+fn socks_request<S: Read + Write>(local: &mut S) -> io::Result<Option<(String, u16)>> {
+    let mut header = [0; 2];
+    local.read_exact(&mut header)?;
+    let mut methods = vec![0; header[1] as usize];
+    local.read_exact(&mut methods)?;
+    if header[0] != 5 || !methods.contains(&0) {
+        // No acceptable method.
+        local.write_all(&[5, 0xff])?;
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    local.write_all(&[5, 0])?;
+    let mut request = [0; 4];
+    local.read_exact(&mut request)?;
+    let host = match request[3] {
+        1 => {
+            let mut addr = [0; 4];
+            local.read_exact(&mut addr)?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        3 => {
+            let mut len = [0; 1];
+            local.read_exact(&mut len)?;
+            let mut name = vec![0; len[0] as usize];
+            local.read_exact(&mut name)?;
+            String::from_utf8(name).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?
+        }
+        4 => {
+            let mut addr = [0; 16];
+            local.read_exact(&mut addr)?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        _ => return Ok(None),
+    };
+    let mut port = [0; 2];
+    local.read_exact(&mut port)?;
+    match request[1] {
+        1 => Ok(Some((host, u16::from_be_bytes(port)))),
+        _ => Ok(None),
+    }
+}
+
+// A SOCKS5 reply with the given status (0 for success). The bound address is not reported.
+fn socks_reply(status: u8) -> [u8; 10] {
+    [5, status, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+// Forward what is ready on either side of the connection, without waiting.
+fn forward(
+    connection: &mut Connection,
+    session: &Session,
+    buf: &mut [u8],
+) -> io::Result<Forwarded> {
+    let mut forwarded = Forwarded::Nothing;
+    match connection.local.read(buf) {
+        Ok(0) => return Ok(Forwarded::Closed),
+        Ok(n) => {
+            write_all(&mut connection.channel, &buf[..n], || {
+                wait_for(session.as_raw_fd(), session_interest(session))
+            })?;
+            forwarded = Forwarded::Data;
+        }
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+        Err(err) => return Err(err),
+    }
+    match connection.channel.read(buf) {
+        Ok(0) if connection.channel.eof() => return Ok(Forwarded::Closed),
+        Ok(0) => {}
+        Ok(n) => {
+            let local_fd = connection.local.as_raw_fd();
+            write_all(&mut connection.local, &buf[..n], || {
+                wait_for(local_fd, Ready::writable())
+            })?;
+            forwarded = Forwarded::Data;
+        }
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+        Err(err) => return Err(err),
+    }
+    Ok(forwarded)
+}
+
+// Write all the data to a non-blocking writer, waiting for its socket while it would block.
+fn write_all<W: Write>(
+    writer: &mut W,
+    mut data: &[u8],
+    wait: impl Fn() -> io::Result<()>,
+) -> io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => wait()?,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// What to wait for on the socket of the session, for what it was blocked on.
+fn session_interest(session: &Session) -> Ready {
+    match session.block_directions() {
+        BlockDirections::Outbound => Ready::writable(),
+        BlockDirections::Both => Ready::readable() | Ready::writable(),
+        // Whatever else it waits for, the bastion may send data for any of the channels.
+        _ => Ready::readable(),
+    }
+}
+
+// Wait until the socket is ready, or the wake interval is over.
+fn wait_for(fd: RawFd, interest: Ready) -> io::Result<()> {
+    let poll = Poll::new()?;
+    poll.register(&EventedFd(&fd), Token(0), interest, PollOpt::level())?;
+    poll.poll(&mut Events::with_capacity(1), Some(WAKE_INTERVAL))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send the greeting of a SOCKS5 client then the given request to a local connection, and
+    // return what the proxy made of the request, with what the client received.
+    fn handshake_over_loopback(request: Vec<u8>) -> (io::Result<(String, u16)>, Vec<u8>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&[5, 1, 0]).unwrap();
+            stream.write_all(&request).unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).unwrap();
+        let target = handshake(&mut local);
+        drop(local);
+        (target, client.join().unwrap())
+    }
+
+    #[test]
+    fn connect_requests_keep_host_names_for_the_bastion() {
+        let mut request = vec![5, 1, 0, 3, 11];
+        request.extend(b"es.internal");
+        request.extend(&9200u16.to_be_bytes());
+        let (target, received) = handshake_over_loopback(request);
+        assert_eq!(target.unwrap(), (String::from("es.internal"), 9200));
+        assert_eq!(received, vec![5, 0]);
+    }
+
+    #[test]
+    fn connect_requests_to_ipv6_addresses_are_read() {
+        let mut request = vec![5, 1, 0, 4];
+        request.extend(&Ipv6Addr::LOCALHOST.octets());
+        request.extend(&443u16.to_be_bytes());
+        let (target, _) = handshake_over_loopback(request);
+        assert_eq!(target.unwrap(), (String::from("::1"), 443));
+    }
+
+    #[test]
+    fn other_commands_are_rejected() {
+        // BIND 127.0.0.1:80
+        let (target, received) = handshake_over_loopback(vec![5, 2, 0, 1, 127, 0, 0, 1, 0, 80]);
+        assert_eq!(target.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let mut expected = vec![5, 0];
+        expected.extend(&socks_reply(7));
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn bastion_port_defaults_to_22() {
+        assert_eq!(
+            bastion_address("bastion.example.com").unwrap(),
+            (String::from("bastion.example.com"), 22)
+        );
+        assert_eq!(
+            bastion_address("bastion.example.com:2222").unwrap(),
+            (String::from("bastion.example.com"), 2222)
+        );
+        assert!(bastion_address("bastion.example.com:ssh").is_err());
+    }
+}